@@ -0,0 +1,30 @@
+use axum::http::{header, HeaderMap, StatusCode};
+
+// checks the `Authorization: Bearer` token of an admin request, every admin route is disabled
+// while no token is configured
+pub(crate) fn authorize(
+    token: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(token) = token else {
+        return Err((StatusCode::FORBIDDEN, "Admin endpoint disabled"));
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // compared in constant time so the token can't be guessed byte by byte
+    let matches = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if matches {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Invalid admin token"))
+    }
+}
@@ -1,92 +1,1061 @@
-use axum::extract::Query;
-use axum::response::IntoResponse;
-use axum::Router;
-use axum::routing::get;
-use serde::Deserialize;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::ops::BitXor;
+use crate::path_error;
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::DefaultBodyLimit;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream::{self, StreamExt};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::{BitXor, Deref};
+use tokio::sync::Semaphore;
+
+// JSON error body shared by all day 2 routes, `field` and `value` point at the offending
+// query parameter when there is one
+#[skip_serializing_none]
+#[derive(Serialize, Debug)]
+struct Day2Error {
+    #[serde(skip)]
+    status: StatusCode,
+    error: String,
+    field: Option<String>,
+    value: Option<String>,
+}
+
+impl Day2Error {
+    fn new(error: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: error.into(),
+            field: None,
+            value: None,
+        }
+    }
+
+    fn field(error: impl Into<String>, field: &str, value: impl ToString) -> Self {
+        Self {
+            field: Some(field.to_string()),
+            value: Some(value.to_string()),
+            ..Self::new(error)
+        }
+    }
+}
+
+impl IntoResponse for Day2Error {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+// a deserialization failure naming the field and, if `lookup` finds it, the offending value
+fn field_error<E: fmt::Display>(
+    err: serde_path_to_error::Error<E>,
+    lookup: impl FnOnce(&str) -> Option<String>,
+) -> Day2Error {
+    let (message, field) = path_error::describe(&err);
+    let value = field.as_deref().and_then(lookup);
+    Day2Error {
+        field,
+        value,
+        ..Day2Error::new(message)
+    }
+}
+
+// like `Query`, but a parse failure is reported as a `Day2Error` naming the field and its value
+struct AddrQuery<T>(T);
+
+impl<T> Deref for AddrQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for AddrQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Day2Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default().as_bytes();
+        let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(query));
+
+        serde_path_to_error::deserialize(deserializer)
+            .map(AddrQuery)
+            .map_err(|err| {
+                field_error(err, |field| {
+                    form_urlencoded::parse(query)
+                        .find(|(key, _)| key == field)
+                        .map(|(_, value)| value.into_owned())
+                })
+            })
+    }
+}
 
 #[derive(Deserialize)]
 struct FromKey {
     from: Ipv4Addr,
     key: Ipv4Addr,
+    mode: Option<Mode>,
 }
 #[derive(Deserialize)]
 struct FromTo {
     from: Ipv4Addr,
     to: Ipv4Addr,
+    mode: Option<Mode>,
 }
 #[derive(Deserialize)]
 struct Ipv6FromKey {
     from: Ipv6Addr,
     key: Ipv6Addr,
+    mode: Option<Mode>,
 }
 #[derive(Deserialize)]
 struct Ipv6FromTo {
     from: Ipv6Addr,
     to: Ipv6Addr,
+    mode: Option<Mode>,
+}
+#[derive(Deserialize)]
+struct AnyFromKey {
+    from: IpAddr,
+    key: IpAddr,
+    mode: Option<Mode>,
+}
+#[derive(Deserialize)]
+struct AnyFromTo {
+    from: IpAddr,
+    to: IpAddr,
+    mode: Option<Mode>,
+}
+#[derive(Deserialize)]
+struct DeriveKey {
+    passphrase: String,
+    salt: String,
+    family: Option<Family>,
+}
+#[derive(Deserialize)]
+struct CidrFromKey {
+    from: IpNet,
+    key: IpAddr,
+}
+#[derive(Deserialize)]
+struct CidrFromTo {
+    from: IpNet,
+    to: IpNet,
+}
+
+// one entry of a batch request, either `{from, key}` or `{from, to}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchItem {
+    from: IpAddr,
+    key: Option<IpAddr>,
+    to: Option<IpAddr>,
+    mode: Option<Mode>,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct BatchResult {
+    index: usize,
+    result: Option<IpAddr>,
+    #[serde(flatten)]
+    error: Option<Day2Error>,
+}
+
+// the network together with the first and last usable host address
+#[derive(Serialize)]
+struct CidrRange {
+    cidr: IpNet,
+    first: IpAddr,
+    last: IpAddr,
+}
+
+impl From<IpNet> for CidrRange {
+    fn from(cidr: IpNet) -> Self {
+        let mut hosts = cidr.hosts();
+        let first = hosts.next().unwrap_or(cidr.network());
+        let last = hosts.next_back().unwrap_or(first);
+        Self { cidr, first, last }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Family {
+    V4,
+    V6,
 }
 
-async fn encryption(fromkey: Query<FromKey>) -> impl IntoResponse {
-    let from = fromkey.from.octets();
-    let key = fromkey.key.octets();
+// transform applied by the `dest` routes, `key` is its inverse: it recovers the key that
+// maps `from` onto `to`, or None when no such key exists
+trait AddressCipher {
+    fn encrypt_v4(&self, from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr;
+    fn key_v4(&self, from: Ipv4Addr, to: Ipv4Addr) -> Option<Ipv4Addr>;
+    fn encrypt_v6(&self, from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr;
+    fn key_v6(&self, from: Ipv6Addr, to: Ipv6Addr) -> Option<Ipv6Addr>;
+}
 
-    let first = from.get(0).unwrap().wrapping_add(*key.get(0).unwrap());
-    let second = from.get(1).unwrap().wrapping_add(*key.get(1).unwrap());
-    let third = from.get(2).unwrap().wrapping_add(*key.get(2).unwrap());
-    let fourth = from.get(3).unwrap().wrapping_add(*key.get(3).unwrap());
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Add,
+    Xor,
+    Rotate,
+    Feistel,
+}
+
+impl Mode {
+    fn cipher(self) -> &'static dyn AddressCipher {
+        match self {
+            Mode::Add => &AddCipher,
+            Mode::Xor => &XorCipher,
+            Mode::Rotate => &RotateCipher,
+            Mode::Feistel => &FeistelCipher,
+        }
+    }
+}
 
-    Ipv4Addr::new(first, second, third, fourth).to_string()
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Add => write!(f, "add"),
+            Mode::Xor => write!(f, "xor"),
+            Mode::Rotate => write!(f, "rotate"),
+            Mode::Feistel => write!(f, "feistel"),
+        }
+    }
 }
-async fn ipv6_encryption(fromkey: Query<Ipv6FromKey>) -> impl IntoResponse {
-    let from = fromkey.from.segments();
-    let key = fromkey.key.segments();
 
-    let first = from.get(0).unwrap().bitxor(*key.get(0).unwrap());
-    let second = from.get(1).unwrap().bitxor(*key.get(1).unwrap());
-    let third = from.get(2).unwrap().bitxor(*key.get(2).unwrap());
-    let fourth = from.get(3).unwrap().bitxor(*key.get(3).unwrap());
-    let fifth = from.get(4).unwrap().bitxor(*key.get(4).unwrap());
-    let sixth = from.get(5).unwrap().bitxor(*key.get(5).unwrap());
-    let seventh = from.get(6).unwrap().bitxor(*key.get(6).unwrap());
-    let eighth = from.get(7).unwrap().bitxor(*key.get(7).unwrap());
+fn zip_octets(a: Ipv4Addr, b: Ipv4Addr, f: impl Fn(u8, u8) -> Option<u8>) -> Option<Ipv4Addr> {
+    let mut octets = [0u8; 4];
+    for (octet, (a, b)) in octets
+        .iter_mut()
+        .zip(a.octets().into_iter().zip(b.octets()))
+    {
+        *octet = f(a, b)?;
+    }
+    Some(Ipv4Addr::from(octets))
+}
 
-    Ipv6Addr::new(first, second, third, fourth, fifth, sixth, seventh, eighth).to_string()
+fn zip_segments(a: Ipv6Addr, b: Ipv6Addr, f: impl Fn(u16, u16) -> Option<u16>) -> Option<Ipv6Addr> {
+    let mut segments = [0u16; 8];
+    for (segment, (a, b)) in segments
+        .iter_mut()
+        .zip(a.segments().into_iter().zip(b.segments()))
+    {
+        *segment = f(a, b)?;
+    }
+    Some(Ipv6Addr::from(segments))
 }
 
-async fn decryption(fromto: Query<FromTo>) -> impl IntoResponse {
-    let from = fromto.from.octets();
-    let to = fromto.to.octets();
+// per-octet / per-segment wrapping addition, the key is recovered by subtraction
+struct AddCipher;
+
+impl AddressCipher for AddCipher {
+    fn encrypt_v4(&self, from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+        zip_octets(from, key, |from, key| Some(from.wrapping_add(key))).unwrap()
+    }
+    fn key_v4(&self, from: Ipv4Addr, to: Ipv4Addr) -> Option<Ipv4Addr> {
+        zip_octets(from, to, |from, to| Some(to.wrapping_sub(from)))
+    }
+    fn encrypt_v6(&self, from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+        zip_segments(from, key, |from, key| Some(from.wrapping_add(key))).unwrap()
+    }
+    fn key_v6(&self, from: Ipv6Addr, to: Ipv6Addr) -> Option<Ipv6Addr> {
+        zip_segments(from, to, |from, to| Some(to.wrapping_sub(from)))
+    }
+}
 
-    let first = to.get(0).unwrap().wrapping_sub(*from.get(0).unwrap());
-    let second = to.get(1).unwrap().wrapping_sub(*from.get(1).unwrap());
-    let third = to.get(2).unwrap().wrapping_sub(*from.get(2).unwrap());
-    let fourth = to.get(3).unwrap().wrapping_sub(*from.get(3).unwrap());
+struct XorCipher;
 
-    Ipv4Addr::new(first, second, third, fourth).to_string()
+impl AddressCipher for XorCipher {
+    fn encrypt_v4(&self, from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+        zip_octets(from, key, |from, key| Some(from.bitxor(key))).unwrap()
+    }
+    fn key_v4(&self, from: Ipv4Addr, to: Ipv4Addr) -> Option<Ipv4Addr> {
+        zip_octets(from, to, |from, to| Some(to.bitxor(from)))
+    }
+    fn encrypt_v6(&self, from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+        zip_segments(from, key, |from, key| Some(from.bitxor(key))).unwrap()
+    }
+    fn key_v6(&self, from: Ipv6Addr, to: Ipv6Addr) -> Option<Ipv6Addr> {
+        zip_segments(from, to, |from, to| Some(to.bitxor(from)))
+    }
 }
 
-async fn ipv6_decryption(fromto: Query<Ipv6FromTo>) -> impl IntoResponse {
-    let from = fromto.from.segments();
-    let to = fromto.to.segments();
+// rotates every octet / segment left by the matching key word, the recovered key is the
+// smallest rotation that works
+struct RotateCipher;
+
+impl AddressCipher for RotateCipher {
+    fn encrypt_v4(&self, from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+        zip_octets(from, key, |from, key| {
+            Some(from.rotate_left(key as u32 % u8::BITS))
+        })
+        .unwrap()
+    }
+    fn key_v4(&self, from: Ipv4Addr, to: Ipv4Addr) -> Option<Ipv4Addr> {
+        zip_octets(from, to, |from, to| {
+            (0..u8::BITS)
+                .find(|&r| from.rotate_left(r) == to)
+                .map(|r| r as u8)
+        })
+    }
+    fn encrypt_v6(&self, from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+        zip_segments(from, key, |from, key| {
+            Some(from.rotate_left(key as u32 % u16::BITS))
+        })
+        .unwrap()
+    }
+    fn key_v6(&self, from: Ipv6Addr, to: Ipv6Addr) -> Option<Ipv6Addr> {
+        zip_segments(from, to, |from, to| {
+            (0..u16::BITS)
+                .find(|&r| from.rotate_left(r) == to)
+                .map(|r| r as u16)
+        })
+    }
+}
+
+// two round Feistel network over the whole address, the key halves are the round keys. With two
+// rounds each round key can be solved for from a single from/to pair, which gives the exact
+// inverse.
+struct FeistelCipher;
+
+fn feistel_round_v4(half: u16, key: u16) -> u16 {
+    (half ^ key).rotate_left(5).wrapping_add(half)
+}
+
+fn feistel_round_key_v4(half: u16, output: u16) -> u16 {
+    output.wrapping_sub(half).rotate_right(5) ^ half
+}
+
+fn feistel_round_v6(half: u64, key: u64) -> u64 {
+    (half ^ key).rotate_left(17).wrapping_add(half)
+}
+
+fn feistel_round_key_v6(half: u64, output: u64) -> u64 {
+    output.wrapping_sub(half).rotate_right(17) ^ half
+}
 
-    let first = to.get(0).unwrap().bitxor(*from.get(0).unwrap());
-    let second = to.get(1).unwrap().bitxor(*from.get(1).unwrap());
-    let third = to.get(2).unwrap().bitxor(*from.get(2).unwrap());
-    let fourth = to.get(3).unwrap().bitxor(*from.get(3).unwrap());
-    let fifth = to.get(4).unwrap().bitxor(*from.get(4).unwrap());
-    let sixth = to.get(5).unwrap().bitxor(*from.get(5).unwrap());
-    let seventh = to.get(6).unwrap().bitxor(*from.get(6).unwrap());
-    let eighth = to.get(7).unwrap().bitxor(*from.get(7).unwrap());
+impl AddressCipher for FeistelCipher {
+    fn encrypt_v4(&self, from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+        let (from, key) = (u32::from(from), u32::from(key));
+        let (left, right) = ((from >> 16) as u16, from as u16);
+        let (left, right) = (right, left ^ feistel_round_v4(right, (key >> 16) as u16));
+        let (left, right) = (right, left ^ feistel_round_v4(right, key as u16));
+        Ipv4Addr::from((left as u32) << 16 | right as u32)
+    }
+    fn key_v4(&self, from: Ipv4Addr, to: Ipv4Addr) -> Option<Ipv4Addr> {
+        let (from, to) = (u32::from(from), u32::from(to));
+        let (from_left, from_right) = ((from >> 16) as u16, from as u16);
+        let (to_left, to_right) = ((to >> 16) as u16, to as u16);
+        let first = feistel_round_key_v4(from_right, from_left ^ to_left);
+        let second = feistel_round_key_v4(to_left, from_right ^ to_right);
+        Some(Ipv4Addr::from((first as u32) << 16 | second as u32))
+    }
+    fn encrypt_v6(&self, from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+        let (from, key) = (u128::from(from), u128::from(key));
+        let (left, right) = ((from >> 64) as u64, from as u64);
+        let (left, right) = (right, left ^ feistel_round_v6(right, (key >> 64) as u64));
+        let (left, right) = (right, left ^ feistel_round_v6(right, key as u64));
+        Ipv6Addr::from((left as u128) << 64 | right as u128)
+    }
+    fn key_v6(&self, from: Ipv6Addr, to: Ipv6Addr) -> Option<Ipv6Addr> {
+        let (from, to) = (u128::from(from), u128::from(to));
+        let (from_left, from_right) = ((from >> 64) as u64, from as u64);
+        let (to_left, to_right) = ((to >> 64) as u64, to as u64);
+        let first = feistel_round_key_v6(from_right, from_left ^ to_left);
+        let second = feistel_round_key_v6(to_left, from_right ^ to_right);
+        Some(Ipv6Addr::from((first as u128) << 64 | second as u128))
+    }
+}
+
+// IPv4 defaults to addition and IPv6 to XOR, as the routes did before modes existed
+const DEFAULT_MODE_V4: Mode = Mode::Add;
+const DEFAULT_MODE_V6: Mode = Mode::Xor;
+
+fn no_key_error(mode: Mode, to: impl ToString) -> Day2Error {
+    Day2Error::field(format!("no key maps from onto to in {mode} mode"), "to", to)
+}
+
+async fn encryption(fromkey: AddrQuery<FromKey>) -> impl IntoResponse {
+    let mode = fromkey.mode.unwrap_or(DEFAULT_MODE_V4);
+    mode.cipher()
+        .encrypt_v4(fromkey.from, fromkey.key)
+        .to_string()
+}
+async fn ipv6_encryption(fromkey: AddrQuery<Ipv6FromKey>) -> impl IntoResponse {
+    let mode = fromkey.mode.unwrap_or(DEFAULT_MODE_V6);
+    mode.cipher()
+        .encrypt_v6(fromkey.from, fromkey.key)
+        .to_string()
+}
+
+async fn decryption(fromto: AddrQuery<FromTo>) -> Result<String, Day2Error> {
+    let mode = fromto.mode.unwrap_or(DEFAULT_MODE_V4);
+    match mode.cipher().key_v4(fromto.from, fromto.to) {
+        Some(key) => Ok(key.to_string()),
+        None => Err(no_key_error(mode, fromto.to)),
+    }
+}
+
+async fn ipv6_decryption(fromto: AddrQuery<Ipv6FromTo>) -> Result<String, Day2Error> {
+    let mode = fromto.mode.unwrap_or(DEFAULT_MODE_V6);
+    match mode.cipher().key_v6(fromto.from, fromto.to) {
+        Some(key) => Ok(key.to_string()),
+        None => Err(no_key_error(mode, fromto.to)),
+    }
+}
+
+// the two operands of a transform in a common family. IPv4-mapped IPv6 addresses
+// (`::ffff:a.b.c.d`) are treated as IPv4 unless the other side is a plain IPv6 address.
+enum AddrPair {
+    // the flag records whether `from` was IPv4-mapped, so the result can be mapped back
+    V4(Ipv4Addr, Ipv4Addr, bool),
+    V6(Ipv6Addr, Ipv6Addr),
+}
+
+impl AddrPair {
+    fn new(from: IpAddr, other: IpAddr, other_name: &str) -> Result<Self, Day2Error> {
+        let as_v4 = |addr: IpAddr| match addr {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(addr) => addr.to_ipv4_mapped(),
+        };
+
+        match (from, other) {
+            (IpAddr::V6(from), IpAddr::V6(other))
+                if from.to_ipv4_mapped().is_none() || other.to_ipv4_mapped().is_none() =>
+            {
+                Ok(AddrPair::V6(from, other))
+            }
+            _ => match (as_v4(from), as_v4(other)) {
+                (Some(from_v4), Some(other_v4)) => {
+                    Ok(AddrPair::V4(from_v4, other_v4, from.is_ipv6()))
+                }
+                _ => {
+                    let (from_family, other_family) = (family(from), family(other));
+                    let error = format!(
+                        "from and {other_name} must be of the same address family, \
+                         got {from_family} from and {other_family} {other_name}"
+                    );
+                    Err(Day2Error::field(error, other_name, other))
+                }
+            },
+        }
+    }
+}
+
+fn family(addr: IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
 
-    Ipv6Addr::new(first, second, third, fourth, fifth, sixth, seventh, eighth).to_string()
+fn v4_result(addr: Ipv4Addr, mapped: bool) -> IpAddr {
+    if mapped {
+        IpAddr::V6(addr.to_ipv6_mapped())
+    } else {
+        IpAddr::V4(addr)
+    }
+}
+
+fn transform_dest(from: IpAddr, key: IpAddr, mode: Option<Mode>) -> Result<IpAddr, Day2Error> {
+    match AddrPair::new(from, key, "key")? {
+        AddrPair::V4(from, key, mapped) => {
+            let cipher = mode.unwrap_or(DEFAULT_MODE_V4).cipher();
+            Ok(v4_result(cipher.encrypt_v4(from, key), mapped))
+        }
+        AddrPair::V6(from, key) => {
+            let cipher = mode.unwrap_or(DEFAULT_MODE_V6).cipher();
+            Ok(IpAddr::V6(cipher.encrypt_v6(from, key)))
+        }
+    }
+}
+
+fn transform_key(from: IpAddr, to: IpAddr, mode: Option<Mode>) -> Result<IpAddr, Day2Error> {
+    let (key, mode) = match AddrPair::new(from, to, "to")? {
+        AddrPair::V4(from, to, mapped) => {
+            let mode = mode.unwrap_or(DEFAULT_MODE_V4);
+            (
+                mode.cipher()
+                    .key_v4(from, to)
+                    .map(|key| v4_result(key, mapped)),
+                mode,
+            )
+        }
+        AddrPair::V6(from, to) => {
+            let mode = mode.unwrap_or(DEFAULT_MODE_V6);
+            (mode.cipher().key_v6(from, to).map(IpAddr::V6), mode)
+        }
+    };
+    key.ok_or_else(|| no_key_error(mode, to))
+}
+
+// PBKDF2-HMAC-SHA256, the derived bytes are used as the key address as is
+const DERIVE_ROUNDS: u32 = 100_000;
+
+fn derive_key(passphrase: &str, salt: &str, family: Family) -> IpAddr {
+    let mut key = [0u8; 16];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        passphrase.as_bytes(),
+        salt.as_bytes(),
+        DERIVE_ROUNDS,
+        &mut key,
+    );
+
+    match family {
+        Family::V4 => IpAddr::V4(Ipv4Addr::new(key[0], key[1], key[2], key[3])),
+        Family::V6 => IpAddr::V6(Ipv6Addr::from(key)),
+    }
+}
+
+// every derivation holds a blocking thread for the whole stretch, so only a few may run at once
+const DERIVE_CONCURRENCY: usize = 4;
+static DERIVE_PERMITS: Semaphore = Semaphore::const_new(DERIVE_CONCURRENCY);
+
+// the passphrase comes in a JSON body rather than the query, which ends up in access logs
+async fn key_derivation(
+    body: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<String, Day2Error> {
+    let DeriveKey {
+        passphrase,
+        salt,
+        family,
+    } = parse_json(&body.map_err(json_rejection)?.0)?;
+    if passphrase.is_empty() {
+        return Err(Day2Error::field(
+            "passphrase must not be empty",
+            "passphrase",
+            passphrase,
+        ));
+    }
+    if salt.is_empty() {
+        return Err(Day2Error::field("salt must not be empty", "salt", salt));
+    }
+
+    let Ok(permit) = DERIVE_PERMITS.try_acquire() else {
+        return Err(Day2Error {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            ..Day2Error::new("too many key derivations in progress, try again later")
+        });
+    };
+
+    // the key stretching takes a while, keep it off the async workers
+    let family = family.unwrap_or(Family::V4);
+    let key = tokio::task::spawn_blocking(move || {
+        let key = derive_key(&passphrase, &salt, family);
+        drop(permit);
+        key
+    })
+    .await
+    .map_err(|err| Day2Error {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        ..Day2Error::new(err.to_string())
+    })?;
+
+    Ok(key.to_string())
+}
+
+async fn any_encryption(fromkey: AddrQuery<AnyFromKey>) -> Result<String, Day2Error> {
+    transform_dest(fromkey.from, fromkey.key, fromkey.mode).map(|addr| addr.to_string())
+}
+
+async fn any_decryption(fromto: AddrQuery<AnyFromTo>) -> Result<String, Day2Error> {
+    transform_key(fromto.from, fromto.to, fromto.mode).map(|addr| addr.to_string())
+}
+
+fn json_rejection(rejection: JsonRejection) -> Day2Error {
+    Day2Error {
+        status: rejection.status(),
+        ..Day2Error::new(rejection.body_text())
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(item: &serde_json::Value) -> Result<T, Day2Error> {
+    serde_path_to_error::deserialize(item).map_err(|err| {
+        field_error(err, |field| {
+            item.get(field).map(|value| match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            })
+        })
+    })
+}
+
+fn transform_batch_item(index: usize, item: serde_json::Value) -> BatchResult {
+    let result = parse_json(&item).and_then(|item: BatchItem| match (item.key, item.to) {
+        (Some(key), None) => transform_dest(item.from, key, item.mode),
+        (None, Some(to)) => transform_key(item.from, to, item.mode),
+        _ => Err(Day2Error::new("exactly one of key or to must be given")),
+    });
+
+    match result {
+        Ok(result) => BatchResult {
+            index,
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => BatchResult {
+            index,
+            result: None,
+            error: Some(error),
+        },
+    }
+}
+
+// the request body is parsed whole, so it's capped at BATCH_BODY_LIMIT bytes (413 beyond that),
+// while results are streamed back one by one as a JSON array
+const BATCH_BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+async fn batch(items: Result<Json<Vec<serde_json::Value>>, JsonRejection>) -> Response {
+    let items = match items {
+        Ok(Json(items)) => items,
+        Err(rejection) => return json_rejection(rejection).into_response(),
+    };
+
+    let len = items.len();
+    if len == 0 {
+        return Json(Vec::<BatchResult>::new()).into_response();
+    }
+
+    let body = stream::iter(items.into_iter().enumerate()).map(move |(index, item)| {
+        let mut chunk = if index == 0 {
+            "[".to_string()
+        } else {
+            ",".to_string()
+        };
+        chunk.push_str(&serde_json::to_string(&transform_batch_item(index, item)).unwrap());
+        if index + 1 == len {
+            chunk.push(']');
+        }
+        Ok::<_, Infallible>(chunk)
+    });
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+// rejects networks like `10.0.0.5/24`, the transforms only make sense on the network address
+fn check_network(field: &str, net: &IpNet) -> Result<(), Day2Error> {
+    if net.addr() != net.network() {
+        return Err(Day2Error::field(
+            format!(
+                "{field} `{net}` has host bits set, did you mean `{}`?",
+                net.trunc()
+            ),
+            field,
+            net,
+        ));
+    }
+    Ok(())
+}
+
+// key bits below the prefix would be dropped from the result, so the key couldn't be recovered
+fn check_key(key: IpAddr, prefix_len: u8) -> Result<(), Day2Error> {
+    let masked = IpNet::new(key, prefix_len).map(|net| net.network());
+    if masked.is_ok_and(|masked| masked != key) {
+        return Err(Day2Error::field(
+            format!("key `{key}` has bits set below the /{prefix_len} prefix"),
+            "key",
+            key,
+        ));
+    }
+    Ok(())
+}
+
+async fn cidr_encryption(fromkey: AddrQuery<CidrFromKey>) -> Result<Json<CidrRange>, Day2Error> {
+    check_network("from", &fromkey.from)?;
+
+    let cidr = match (fromkey.from, fromkey.key) {
+        (IpNet::V4(from), IpAddr::V4(key)) => {
+            check_key(key.into(), from.prefix_len())?;
+            let network = AddCipher.encrypt_v4(from.network(), key);
+            IpNet::V4(Ipv4Net::new(network, from.prefix_len()).unwrap().trunc())
+        }
+        (IpNet::V6(from), IpAddr::V6(key)) => {
+            check_key(key.into(), from.prefix_len())?;
+            let network = XorCipher.encrypt_v6(from.network(), key);
+            IpNet::V6(Ipv6Net::new(network, from.prefix_len()).unwrap().trunc())
+        }
+        _ => {
+            return Err(Day2Error::field(
+                "from and key must be of the same address family",
+                "key",
+                fromkey.key,
+            ))
+        }
+    };
+
+    Ok(Json(cidr.into()))
+}
+
+async fn cidr_decryption(fromto: AddrQuery<CidrFromTo>) -> Result<Json<CidrRange>, Day2Error> {
+    check_network("from", &fromto.from)?;
+    check_network("to", &fromto.to)?;
+
+    if fromto.from.prefix_len() != fromto.to.prefix_len() {
+        return Err(Day2Error::field(
+            format!(
+                "prefix lengths differ: from is /{}, to is /{}",
+                fromto.from.prefix_len(),
+                fromto.to.prefix_len()
+            ),
+            "to",
+            fromto.to,
+        ));
+    }
+
+    let cidr = match (fromto.from, fromto.to) {
+        (IpNet::V4(from), IpNet::V4(to)) => {
+            let key = AddCipher.key_v4(from.network(), to.network()).unwrap();
+            IpNet::V4(Ipv4Net::new(key, from.prefix_len()).unwrap().trunc())
+        }
+        (IpNet::V6(from), IpNet::V6(to)) => {
+            let key = XorCipher.key_v6(from.network(), to.network()).unwrap();
+            IpNet::V6(Ipv6Net::new(key, from.prefix_len()).unwrap().trunc())
+        }
+        _ => {
+            return Err(Day2Error::field(
+                "from and to must be of the same address family",
+                "to",
+                fromto.to,
+            ))
+        }
+    };
+
+    Ok(Json(cidr.into()))
 }
 
 pub fn day2_routes(router: Router) -> Router {
     let router = router.route("/2/dest", get(encryption));
     let router = router.route("/2/key", get(decryption));
+    let router = router.route("/2/key/derive", post(key_derivation));
 
     let router = router.route("/2/v6/dest", get(ipv6_encryption));
     let router = router.route("/2/v6/key", get(ipv6_decryption));
-    router
-}
\ No newline at end of file
+
+    let router = router.route("/2/any/dest", get(any_encryption));
+    let router = router.route("/2/any/key", get(any_decryption));
+
+    let router = router.route("/2/cidr/dest", get(cidr_encryption));
+    let router = router.route("/2/cidr/key", get(cidr_decryption));
+
+    router.route(
+        "/2/batch",
+        post(batch).layer(DefaultBodyLimit::max(BATCH_BODY_LIMIT)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [Mode; 4] = [Mode::Add, Mode::Xor, Mode::Rotate, Mode::Feistel];
+
+    // xorshift, enough to spread the samples over the whole address space
+    fn samples(count: usize) -> impl Iterator<Item = u128> {
+        let mut state = 0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c834_u128;
+        std::iter::repeat_with(move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        })
+        .take(count)
+    }
+
+    // rotations are only unique below the word size, and not at all for words like 0x00 or 0xff
+    fn rotate_key_v4(key: u128) -> Ipv4Addr {
+        Ipv4Addr::from(key as u32 & 0x0707_0707)
+    }
+
+    fn rotate_key_v6(key: u128) -> Ipv6Addr {
+        Ipv6Addr::from(key & 0x000f_000f_000f_000f_000f_000f_000f_000f)
+    }
+
+    #[test]
+    fn keys_round_trip_v4() {
+        let from = Ipv4Addr::new(0x12, 0x34, 0x56, 0x78);
+        for mode in MODES {
+            let cipher = mode.cipher();
+            for sample in samples(1000) {
+                let key = match mode {
+                    Mode::Rotate => rotate_key_v4(sample),
+                    _ => Ipv4Addr::from(sample as u32),
+                };
+                let to = cipher.encrypt_v4(from, key);
+                assert_eq!(cipher.key_v4(from, to), Some(key), "{mode} {from} {key}");
+            }
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_v6() {
+        let from = Ipv6Addr::new(
+            0x1234, 0x5678, 0x9abc, 0xdef0, 0x1357, 0x2468, 0xace1, 0x3579,
+        );
+        for mode in MODES {
+            let cipher = mode.cipher();
+            for sample in samples(1000) {
+                let key = match mode {
+                    Mode::Rotate => rotate_key_v6(sample),
+                    _ => Ipv6Addr::from(sample),
+                };
+                let to = cipher.encrypt_v6(from, key);
+                assert_eq!(cipher.key_v6(from, to), Some(key), "{mode} {from} {key}");
+            }
+        }
+    }
+
+    #[test]
+    fn feistel_inverts_for_any_from() {
+        let cipher = Mode::Feistel.cipher();
+        for (from, key) in samples(1000).zip(samples(1001).skip(1)) {
+            let (from_v4, key_v4) = (Ipv4Addr::from(from as u32), Ipv4Addr::from(key as u32));
+            let to_v4 = cipher.encrypt_v4(from_v4, key_v4);
+            assert_eq!(cipher.key_v4(from_v4, to_v4), Some(key_v4));
+
+            let (from_v6, key_v6) = (Ipv6Addr::from(from), Ipv6Addr::from(key));
+            let to_v6 = cipher.encrypt_v6(from_v6, key_v6);
+            assert_eq!(cipher.key_v6(from_v6, to_v6), Some(key_v6));
+        }
+    }
+
+    #[test]
+    fn rotate_key_is_smallest_rotation() {
+        let cipher = Mode::Rotate.cipher();
+        // 0xff rotates onto itself, 0x55 repeats every two bits
+        let from = Ipv4Addr::new(0xff, 0x55, 0x01, 0x80);
+        let to = cipher.encrypt_v4(from, Ipv4Addr::new(3, 3, 3, 3));
+        assert_eq!(to, Ipv4Addr::new(0xff, 0xaa, 0x08, 0x04));
+        assert_eq!(cipher.key_v4(from, to), Some(Ipv4Addr::new(0, 1, 3, 3)));
+        assert_eq!(cipher.encrypt_v4(from, Ipv4Addr::new(0, 1, 3, 3)), to);
+
+        // not a rotation of the from octet at all
+        assert_eq!(
+            cipher.key_v4(from, Ipv4Addr::new(0xff, 0x55, 0x03, 0x80)),
+            None
+        );
+        let from = Ipv6Addr::new(1, 0, 0, 0, 0, 0, 0, 0);
+        assert_eq!(
+            cipher.key_v6(from, Ipv6Addr::new(3, 0, 0, 0, 0, 0, 0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn default_modes() {
+        let from: IpAddr = "10.0.0.0".parse().unwrap();
+        let key: IpAddr = "1.2.3.255".parse().unwrap();
+        let to = transform_dest(from, key, None).unwrap();
+        assert_eq!(to, "11.2.3.255".parse::<IpAddr>().unwrap());
+        assert_eq!(to, transform_dest(from, key, Some(Mode::Add)).unwrap());
+        assert_eq!(transform_key(from, to, None).unwrap(), key);
+
+        let from: IpAddr = "fe80::1".parse().unwrap();
+        let key: IpAddr = "5:6:7::3333".parse().unwrap();
+        let to = transform_dest(from, key, None).unwrap();
+        assert_eq!(to, "fe85:6:7::3332".parse::<IpAddr>().unwrap());
+        assert_eq!(to, transform_dest(from, key, Some(Mode::Xor)).unwrap());
+        assert_eq!(transform_key(from, to, None).unwrap(), key);
+    }
+
+    #[test]
+    fn keys_round_trip_through_routes() {
+        // every octet / segment has distinct rotations, so rotate keys are unique too
+        let from_v4: IpAddr = "192.168.12.35".parse().unwrap();
+        let from_v6: IpAddr = "2001:db8:1:2:ff01:42:8329:7".parse().unwrap();
+        for mode in MODES {
+            for sample in samples(100) {
+                let (key_v4, key_v6) = match mode {
+                    Mode::Rotate => (rotate_key_v4(sample), rotate_key_v6(sample)),
+                    _ => (Ipv4Addr::from(sample as u32), Ipv6Addr::from(sample)),
+                };
+                for (from, key) in [(from_v4, IpAddr::V4(key_v4)), (from_v6, IpAddr::V6(key_v6))] {
+                    let to = transform_dest(from, key, Some(mode)).unwrap();
+                    assert_eq!(transform_key(from, to, Some(mode)).unwrap(), key, "{mode}");
+                }
+            }
+        }
+    }
+
+    fn encrypt_cidr(from: &str, key: &str) -> Result<CidrRange, Day2Error> {
+        let query = CidrFromKey {
+            from: from.parse().unwrap(),
+            key: key.parse().unwrap(),
+        };
+        futures::executor::block_on(cidr_encryption(AddrQuery(query))).map(|Json(range)| range)
+    }
+
+    fn decrypt_cidr(from: &str, to: &str) -> Result<CidrRange, Day2Error> {
+        let query = CidrFromTo {
+            from: from.parse().unwrap(),
+            to: to.parse().unwrap(),
+        };
+        futures::executor::block_on(cidr_decryption(AddrQuery(query))).map(|Json(range)| range)
+    }
+
+    fn range(range: CidrRange) -> (String, String, String) {
+        (
+            range.cidr.to_string(),
+            range.first.to_string(),
+            range.last.to_string(),
+        )
+    }
+
+    fn rejected_field(result: Result<CidrRange, Day2Error>) -> Option<String> {
+        result.err().expect("should be rejected").field
+    }
+
+    #[test]
+    fn cidr_rejects_host_bits() {
+        assert_eq!(
+            rejected_field(encrypt_cidr("10.0.0.1/24", "1.2.3.0")).as_deref(),
+            Some("from")
+        );
+        assert_eq!(
+            rejected_field(encrypt_cidr("fd00::1/64", "::")).as_deref(),
+            Some("from")
+        );
+        assert_eq!(
+            rejected_field(decrypt_cidr("10.0.0.0/24", "10.0.1.1/24")).as_deref(),
+            Some("to")
+        );
+        assert_eq!(
+            rejected_field(decrypt_cidr("10.0.0.128/24", "10.0.1.0/24")).as_deref(),
+            Some("from")
+        );
+        assert!(encrypt_cidr("10.0.0.0/24", "1.2.3.0").is_ok());
+    }
+
+    #[test]
+    fn cidr_rejects_key_bits_below_the_prefix() {
+        let err = encrypt_cidr("10.0.0.0/24", "1.2.3.4").err().unwrap();
+        assert_eq!(err.field.as_deref(), Some("key"));
+        assert_eq!(err.value.as_deref(), Some("1.2.3.4"));
+        assert!(err.error.contains("/24"), "{}", err.error);
+        assert!(encrypt_cidr("fd00::/64", "::1").is_err());
+        assert!(encrypt_cidr("fd00::/64", "1:2:3:4::").is_ok());
+        // a /0 keeps nothing of the key and /32 and /128 keep all of it
+        assert!(encrypt_cidr("0.0.0.0/0", "0.0.0.1").is_err());
+        assert!(encrypt_cidr("0.0.0.0/0", "0.0.0.0").is_ok());
+        assert!(encrypt_cidr("10.0.0.1/32", "255.255.255.255").is_ok());
+        assert!(encrypt_cidr("fd00::1/128", "ffff::ffff").is_ok());
+        // mixed families are reported as such rather than as key bits
+        let err = encrypt_cidr("10.0.0.0/8", "::1").err().unwrap();
+        assert!(err.error.contains("same address family"), "{}", err.error);
+        assert!(decrypt_cidr("10.0.0.0/8", "fd00::/8").is_err());
+        assert_eq!(
+            rejected_field(decrypt_cidr("10.0.0.0/8", "10.0.0.0/16")).as_deref(),
+            Some("to")
+        );
+    }
+
+    #[test]
+    fn cidr_first_and_last_hosts() {
+        let cases = [
+            (
+                ("10.0.0.0/24", "1.2.3.0"),
+                ("11.2.3.0/24", "11.2.3.1", "11.2.3.254"),
+            ),
+            (
+                ("10.0.0.0/30", "0.0.0.4"),
+                ("10.0.0.4/30", "10.0.0.5", "10.0.0.6"),
+            ),
+            // /31 point-to-point links and /32 hosts have no network or broadcast address
+            (
+                ("10.0.0.0/31", "0.0.0.2"),
+                ("10.0.0.2/31", "10.0.0.2", "10.0.0.3"),
+            ),
+            (
+                ("10.0.0.1/32", "0.0.0.1"),
+                ("10.0.0.2/32", "10.0.0.2", "10.0.0.2"),
+            ),
+            (("fd00::/127", "::2"), ("fd00::2/127", "fd00::2", "fd00::3")),
+            (
+                ("fd00::1/128", "::3"),
+                ("fd00::2/128", "fd00::2", "fd00::2"),
+            ),
+        ];
+        for ((from, key), (cidr, first, last)) in cases {
+            let expected = (cidr.to_string(), first.to_string(), last.to_string());
+            assert_eq!(
+                range(encrypt_cidr(from, key).unwrap()),
+                expected,
+                "{from} {key}"
+            );
+            let key_range = range(decrypt_cidr(from, cidr).unwrap());
+            assert_eq!(
+                key_range.0.parse::<IpNet>().unwrap().addr().to_string(),
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn addr_pairs_share_a_family() {
+        let pair = |from: &str, other: &str| {
+            AddrPair::new(from.parse().unwrap(), other.parse().unwrap(), "key")
+        };
+        let v4 = |a: &str| a.parse::<Ipv4Addr>().unwrap();
+
+        assert!(matches!(
+            pair("1.2.3.4", "5.6.7.8"),
+            Ok(AddrPair::V4(from, key, false)) if from == v4("1.2.3.4") && key == v4("5.6.7.8")
+        ));
+        // IPv4-mapped addresses pair with plain IPv4 either way round
+        assert!(matches!(
+            pair("::ffff:1.2.3.4", "5.6.7.8"),
+            Ok(AddrPair::V4(from, key, true)) if from == v4("1.2.3.4") && key == v4("5.6.7.8")
+        ));
+        assert!(matches!(
+            pair("1.2.3.4", "::ffff:5.6.7.8"),
+            Ok(AddrPair::V4(_, key, false)) if key == v4("5.6.7.8")
+        ));
+        assert!(matches!(
+            pair("::ffff:1.2.3.4", "::ffff:5.6.7.8"),
+            Ok(AddrPair::V4(_, _, true))
+        ));
+        // against a plain IPv6 address a mapped one stays IPv6
+        assert!(matches!(
+            pair("::ffff:1.2.3.4", "fd00::1"),
+            Ok(AddrPair::V6(..))
+        ));
+        assert!(matches!(
+            pair("fd00::1", "::ffff:5.6.7.8"),
+            Ok(AddrPair::V6(..))
+        ));
+        assert!(matches!(pair("fd00::1", "fd00::2"), Ok(AddrPair::V6(..))));
+
+        for (from, other, families) in [
+            ("1.2.3.4", "fd00::1", "got IPv4 from and IPv6 key"),
+            ("fd00::1", "5.6.7.8", "got IPv6 from and IPv4 key"),
+        ] {
+            let err = pair(from, other).err().unwrap();
+            assert!(err.error.ends_with(families), "{}", err.error);
+            assert_eq!(err.field.as_deref(), Some("key"));
+            assert_eq!(err.value.as_deref(), Some(other));
+        }
+    }
+}
@@ -1,12 +1,34 @@
-use std::fmt;
-use axum::http::{HeaderMap, StatusCode};
-use axum::response::IntoResponse;
-use axum::Router;
-use axum::routing::post;
-use cargo_manifest::Manifest;
-use serde::Deserialize;
-use serde_with::serde_as;
+use crate::admin;
+use crate::format::{render, Format};
+use crate::path_error;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use cargo_manifest::{
+    Dependency, DepsSet, Edition, Manifest, MaybeInherited, Package, WorkspacePackage,
+};
+use futures::stream;
+use semver::{Version, VersionReq};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{DeserializeOwned, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_path_to_error::Segment;
+use serde_with::skip_serializing_none;
+use serde_yaml::Value as YamlValue;
 use shuttle_runtime::__internals::serde_json;
+use shuttle_runtime::__internals::serde_json::Value;
+use shuttle_runtime::SecretStore;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, fs, io};
+use tokio::sync::Notify;
+use tower_http::decompression::RequestDecompressionLayer;
 
 #[derive(Deserialize, Default)]
 struct Metadata {
@@ -14,84 +36,1723 @@ struct Metadata {
     pub orders: Vec<Order>,
 }
 
-#[serde_as]
+// orders may sit in `[package.metadata]` as well as `[workspace.metadata]`
+type OrderManifest = Manifest<Metadata, Metadata>;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Section {
+    Package,
+    Workspace,
+    // a workspace member manifest uploaded next to the root one, by package name
+    Member(String),
+}
+
 #[derive(Deserialize, Debug)]
 struct Order {
     pub item: String,
-    #[serde_as(deserialize_as = "serde_with::DefaultOnError")]
+    // kept as given, so that a report can say why an order was dropped
+    #[serde(default, deserialize_with = "quantity_value")]
+    pub quantity: Option<Value>,
+}
+
+// any value, like `Value` itself. YAML hands integers beyond 64 bits over as 128-bit ones, which
+// `Value` can't hold, so they become floats as they do when JSON is parsed
+fn quantity_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    struct QuantityVisitor;
+
+    impl<'de> Visitor<'de> for QuantityVisitor {
+        type Value = Value;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("any value")
+        }
+
+        fn visit_bool<E>(self, value: bool) -> Result<Value, E> {
+            Ok(Value::Bool(value))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Value, E> {
+            Ok(Value::from(value))
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Value, E> {
+            Ok(Value::from(value))
+        }
+
+        fn visit_i128<E>(self, value: i128) -> Result<Value, E> {
+            Ok(Value::from(value as f64))
+        }
+
+        fn visit_u128<E>(self, value: u128) -> Result<Value, E> {
+            Ok(Value::from(value as f64))
+        }
+
+        fn visit_f64<E>(self, value: f64) -> Result<Value, E> {
+            Ok(Value::from(value))
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Value, E> {
+            Ok(Value::from(value))
+        }
+
+        fn visit_none<E>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_unit<E>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Value, A::Error> {
+            Value::deserialize(SeqAccessDeserializer::new(seq))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Value, A::Error> {
+            Value::deserialize(MapAccessDeserializer::new(map))
+        }
+    }
+
+    deserializer.deserialize_any(QuantityVisitor).map(Some)
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RejectReason {
+    MissingQuantity,
+    Negative,
+    NonInteger,
+    Overflow,
+    InvalidType,
+}
+
+impl Order {
+    fn quantity(&self) -> Result<u32, RejectReason> {
+        let number = match &self.quantity {
+            None | Some(Value::Null) => return Err(RejectReason::MissingQuantity),
+            Some(Value::Number(number)) => number,
+            Some(_) => return Err(RejectReason::InvalidType),
+        };
+
+        if let Some(quantity) = number.as_u64() {
+            return u32::try_from(quantity).map_err(|_| RejectReason::Overflow);
+        }
+        if number.as_i64().is_some() {
+            return Err(RejectReason::Negative);
+        }
+        // integers beyond the 64-bit range end up as floats
+        match number.as_f64() {
+            Some(float) if float.fract() == 0.0 && float.abs() > i64::MAX as f64 => {
+                if float < 0.0 {
+                    Err(RejectReason::Negative)
+                } else {
+                    Err(RejectReason::Overflow)
+                }
+            }
+            _ => Err(RejectReason::NonInteger),
+        }
+    }
+}
+
+// which keywords a manifest must carry, read from the secrets at startup:
+// DAY5_KEYWORDS (comma separated), DAY5_KEYWORDS_MATCH (`any` or `all`),
+// DAY5_KEYWORDS_CASE_SENSITIVE and DAY5_MATCH_CATEGORIES
+#[derive(Debug, Clone)]
+struct KeywordPolicy {
+    keywords: Vec<String>,
+    require_all: bool,
+    case_sensitive: bool,
+    match_categories: bool,
+}
+
+impl Default for KeywordPolicy {
+    fn default() -> Self {
+        Self {
+            keywords: vec!["Christmas 2024".to_string()],
+            require_all: false,
+            case_sensitive: true,
+            match_categories: false,
+        }
+    }
+}
+
+impl KeywordPolicy {
+    fn from_secrets(secrets: &SecretStore) -> Self {
+        let default = Self::default();
+        let flag = |key: &str, default: bool| match secrets.get(key) {
+            Some(value) => value
+                .parse::<bool>()
+                .unwrap_or_else(|_| panic!("{key} must be true or false")),
+            None => default,
+        };
+
+        let keywords = secrets
+            .get("DAY5_KEYWORDS")
+            .map(|keywords| {
+                let keywords: Vec<String> = keywords
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|keyword| !keyword.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if keywords.is_empty() {
+                    panic!("DAY5_KEYWORDS must list at least one keyword");
+                }
+                keywords
+            })
+            .unwrap_or(default.keywords);
+        let require_all = match secrets.get("DAY5_KEYWORDS_MATCH").as_deref() {
+            None | Some("any") => false,
+            Some("all") => true,
+            Some(_) => panic!("DAY5_KEYWORDS_MATCH must be any or all"),
+        };
+
+        Self {
+            keywords,
+            require_all,
+            case_sensitive: flag("DAY5_KEYWORDS_CASE_SENSITIVE", default.case_sensitive),
+            match_categories: flag("DAY5_MATCH_CATEGORIES", default.match_categories),
+        }
+    }
+
+    fn matches(&self, keywords: &[String], categories: &[String]) -> bool {
+        let categories = if self.match_categories {
+            categories
+        } else {
+            &[]
+        };
+        let found = |wanted: &String| {
+            keywords.iter().chain(categories).any(|given| {
+                if self.case_sensitive {
+                    given == wanted
+                } else {
+                    given.to_lowercase() == wanted.to_lowercase()
+                }
+            })
+        };
+
+        if self.require_all {
+            self.keywords.iter().all(found)
+        } else {
+            self.keywords.iter().any(found)
+        }
+    }
+}
+
+// resolves `field.workspace = true` against the workspace's `[workspace.package]`
+fn inherit<T>(
+    field: Option<MaybeInherited<T>>,
+    workspace: Option<&WorkspacePackage>,
+    from_workspace: impl FnOnce(&WorkspacePackage) -> Option<T>,
+) -> Option<T> {
+    match field? {
+        MaybeInherited::Local(value) => Some(value),
+        MaybeInherited::Inherited { .. } => workspace.and_then(from_workspace),
+    }
+}
+
+#[derive(Deserialize)]
+struct ManifestQuery {
     #[serde(default)]
-    pub quantity: Option<u32>,
+    report: bool,
+}
+
+#[derive(Serialize)]
+struct OrderLine<'a> {
+    item: &'a str,
+    quantity: u32,
+    section: &'a Section,
 }
 
-impl fmt::Display for Order {
+impl fmt::Display for OrderLine<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.quantity {
-            Some(num) => write!(f, "{}: {:?}", self.item, num),
-            None => write!(f, ""),
+        write!(f, "{}: {}", self.item, self.quantity)
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct RejectedOrder<'a> {
+    item: &'a str,
+    // as given, rendered as a string so any value fits every response format
+    quantity: Option<String>,
+    reason: RejectReason,
+    section: &'a Section,
+}
+
+// everything found in the manifest, duplicate items are summed up in `totals`
+#[derive(Serialize)]
+struct OrderReport<'a> {
+    orders: Vec<OrderLine<'a>>,
+    totals: BTreeMap<&'a str, u64>,
+    rejected: Vec<RejectedOrder<'a>>,
+}
+
+impl<'a> OrderReport<'a> {
+    fn new(orders: &'a [(Section, Order)]) -> Self {
+        let mut report = OrderReport {
+            orders: vec![],
+            totals: BTreeMap::new(),
+            rejected: vec![],
+        };
+        for (section, order) in orders {
+            match order.quantity() {
+                Ok(quantity) => {
+                    *report.totals.entry(&order.item).or_default() += quantity as u64;
+                    report.orders.push(OrderLine {
+                        item: &order.item,
+                        quantity,
+                        section,
+                    });
+                }
+                Err(reason) => report.rejected.push(RejectedOrder {
+                    item: &order.item,
+                    quantity: order.quantity.as_ref().and_then(|quantity| match quantity {
+                        Value::Null => None,
+                        Value::String(quantity) => Some(quantity.clone()),
+                        quantity => Some(quantity.to_string()),
+                    }),
+                    reason,
+                    section,
+                }),
+            }
+        }
+        report
+    }
+}
+
+// TOML documents can't have an array at the top level
+#[derive(Serialize)]
+struct OrderLines<'a> {
+    orders: &'a [OrderLine<'a>],
+}
+
+fn render_orders(format: Format, orders: &[OrderLine]) -> Response {
+    match format {
+        Format::Text => {
+            let body = orders
+                .iter()
+                .map(|order| order.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, format.content_type())],
+                body,
+            )
+                .into_response()
+        }
+        Format::Toml => render(format, &OrderLines { orders }),
+        _ => render(format, &orders),
+    }
+}
+
+// JSON error body for manifests that fail to parse, `line` and `column` are 1-based and `path` is
+// the key path serde was at when it failed, e.g. `package.metadata.orders[0].quantity`
+#[skip_serializing_none]
+#[derive(Serialize, Debug)]
+struct ManifestError {
+    #[serde(skip)]
+    status: StatusCode,
+    error: &'static str,
+    message: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    path: Option<String>,
+    // the multipart part the document came from
+    part: Option<&'static str>,
+}
+
+impl ManifestError {
+    fn new(status: StatusCode, error: &'static str) -> Self {
+        Self {
+            status,
+            error,
+            message: None,
+            line: None,
+            column: None,
+            path: None,
+            part: None,
+        }
+    }
+
+    fn invalid<E: fmt::Display>(
+        err: serde_path_to_error::Error<E>,
+        message: String,
+        location: Option<(usize, usize)>,
+    ) -> Self {
+        let path = path_error::error_field(err.path(), &message);
+        Self {
+            message: Some(message),
+            line: location.map(|(line, _)| line),
+            column: location.map(|(_, column)| column),
+            path,
+            ..Self::new(StatusCode::BAD_REQUEST, "Invalid manifest")
+        }
+    }
+}
+
+impl IntoResponse for ManifestError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+// line and column of a byte offset
+fn location(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+// `serde_json` and `serde_yaml` append the location to their messages
+fn strip_location(message: String, line: usize, column: usize) -> String {
+    let suffix = format!(" at line {line} column {column}");
+    message
+        .strip_suffix(&suffix)
+        .map(str::to_string)
+        .unwrap_or(message)
+}
+
+// `serde_yaml` starts its messages with the key path it was at, `path` or a parent of it since its
+// path stops where serde buffers a value, e.g. for an untagged enum
+fn strip_path(message: String, path: &serde_path_to_error::Path) -> String {
+    let mut prefixes = vec![];
+    let mut prefix = String::new();
+    for segment in path {
+        if !prefix.is_empty() && !matches!(segment, Segment::Seq { .. }) {
+            prefix.push('.');
+        }
+        prefix.push_str(&segment.to_string());
+        prefixes.push(prefix.clone());
+    }
+    prefixes
+        .iter()
+        .rev()
+        .find_map(|prefix| message.strip_prefix(prefix.as_str())?.strip_prefix(": "))
+        .map(str::to_string)
+        .unwrap_or(message)
+}
+
+fn parse_document<T: DeserializeOwned>(format: Format, text: &str) -> Result<T, ManifestError> {
+    match format {
+        Format::Toml => {
+            serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(|err| {
+                let message = err.inner().message().to_string();
+                let location = err.inner().span().map(|span| location(text, span.start));
+                ManifestError::invalid(err, message, location)
+            })
+        }
+        Format::Json => serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(
+            text,
+        ))
+        .map_err(|err| {
+            let (line, column) = (err.inner().line(), err.inner().column());
+            let message = strip_location(err.inner().to_string(), line, column);
+            ManifestError::invalid(err, message, (line > 0).then_some((line, column)))
+        }),
+        Format::Yaml => serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text))
+            .map_err(|err| {
+                let location = err
+                    .inner()
+                    .location()
+                    .map(|location| (location.line(), location.column()));
+                let message = match location {
+                    Some((line, column)) => strip_location(err.inner().to_string(), line, column),
+                    None => err.inner().to_string(),
+                };
+                let message = strip_path(message, err.path());
+                ManifestError::invalid(err, message, location)
+            }),
+        Format::Text => Err(ManifestError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported content type",
+        )),
+    }
+}
+
+fn parse_manifest<T: DeserializeOwned>(
+    content_type: Option<&str>,
+    text: &str,
+) -> Result<T, ManifestError> {
+    let Some(content_type) = content_type else {
+        return Err(ManifestError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content type not set",
+        ));
+    };
+    match Format::from_content_type(content_type) {
+        Some(format) => parse_document(format, text),
+        None => Err(ManifestError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported content type",
+        )),
+    }
+}
+
+// `workspace` is the `[workspace.package]` inherited values are resolved against, a virtual
+// workspace manifest has no package and its keywords live there directly
+fn has_magic_keyword(
+    policy: &KeywordPolicy,
+    package: Option<&Package<Metadata>>,
+    workspace: Option<&WorkspacePackage>,
+) -> bool {
+    let (keywords, categories) = match package {
+        Some(package) => (
+            inherit(package.keywords.clone(), workspace, |p| p.keywords.clone()),
+            inherit(package.categories.clone(), workspace, |p| {
+                p.categories.clone()
+            }),
+        ),
+        None => (
+            workspace.and_then(|p| p.keywords.clone()),
+            workspace.and_then(|p| p.categories.clone()),
+        ),
+    };
+    policy.matches(
+        &keywords.unwrap_or_default(),
+        &categories.unwrap_or_default(),
+    )
+}
+
+fn section_orders(
+    section: Section,
+    metadata: Option<Metadata>,
+) -> impl Iterator<Item = (Section, Order)> {
+    let orders = metadata.map(|metadata| metadata.orders).unwrap_or_default();
+    orders
+        .into_iter()
+        .map(move |order| (section.clone(), order))
+}
+
+#[derive(Clone)]
+struct Day5 {
+    policy: Arc<KeywordPolicy>,
+    book: OrderBook,
+    // `DAY5_ADMIN_TOKEN`, needed to delete booked orders
+    admin_token: Option<Arc<str>>,
+}
+
+async fn toml_orders(
+    State(state): State<Day5>,
+    Query(query): Query<ManifestQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type.to_str().unwrap_or_default());
+    if let Some(boundary) =
+        content_type.and_then(|content_type| multer::parse_boundary(content_type).ok())
+    {
+        return upload_orders(&state, &headers, body, boundary).await;
+    }
+
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(err) => {
+            let message = Some(err.utf8_error().to_string());
+            return ManifestError {
+                message,
+                ..ManifestError::new(StatusCode::BAD_REQUEST, "Invalid manifest")
+            }
+            .into_response();
+        }
+    };
+    let manifest = match parse_manifest::<OrderManifest>(content_type, &text) {
+        Ok(manifest) => manifest,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let (mut package, workspace) = match (manifest.package, manifest.workspace) {
+        (None, None) => {
+            return (StatusCode::BAD_REQUEST, "No package or workspace found").into_response()
+        }
+        sections => sections,
+    };
+
+    let workspace_package = workspace
+        .as_ref()
+        .and_then(|workspace| workspace.package.clone());
+    if !has_magic_keyword(&state.policy, package.as_ref(), workspace_package.as_ref()) {
+        // a member manifest on its own has nothing to inherit from
+        let inherited = package.as_ref().is_some_and(|package| {
+            matches!(package.keywords, Some(MaybeInherited::Inherited { .. }))
+                || matches!(package.categories, Some(MaybeInherited::Inherited { .. }))
+        });
+        if inherited && workspace_package.is_none() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Magic keyword not provided, inherited keywords need the workspace manifest: \
+                 upload it as the multipart `manifest` part and this one as a `member` part",
+            )
+                .into_response();
+        }
+        return (StatusCode::BAD_REQUEST, "Magic keyword not provided").into_response();
+    }
+
+    let package_metadata = package.as_mut().and_then(|package| package.metadata.take());
+    let workspace_metadata = workspace.and_then(|workspace| workspace.metadata);
+    let no_metadata = package_metadata.is_none() && workspace_metadata.is_none();
+
+    let orders = section_orders(Section::Package, package_metadata)
+        .chain(section_orders(Section::Workspace, workspace_metadata))
+        .collect::<Vec<_>>();
+
+    // a virtual workspace has no name to book its orders under
+    if let Some(package) = &package {
+        state
+            .book
+            .record([book_entry(package, workspace_package.as_ref(), &orders)]);
+    }
+
+    if !query.report && no_metadata {
+        return (StatusCode::NO_CONTENT, "No metadata found").into_response();
+    }
+
+    if query.report {
+        return render(Format::from_accept(&headers), &OrderReport::new(&orders));
+    }
+
+    let orders = orders
+        .iter()
+        .filter_map(|(section, order)| {
+            let quantity = order.quantity().ok()?;
+            Some(OrderLine {
+                item: &order.item,
+                quantity,
+                section,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if orders.is_empty() {
+        return (StatusCode::NO_CONTENT, "No orders found").into_response();
+    }
+    render_orders(Format::from_accept(&headers), &orders)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BookedOrder {
+    item: String,
+    quantity: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BookEntry {
+    name: String,
+    version: String,
+    orders: Vec<BookedOrder>,
+}
+
+// valid orders of every submitted package, a resubmission of the same name and version replaces
+// its entry. Holds at most `DAY5_ORDER_BOOK_MAX` entries and is written to `DAY5_ORDER_BOOK_PATH`
+// as JSON in the background after changes when that is set.
+#[derive(Clone)]
+struct OrderBook {
+    path: Option<Arc<PathBuf>>,
+    max_entries: usize,
+    entries: Arc<Mutex<BTreeMap<(String, String), BookEntry>>>,
+    changed: Arc<Notify>,
+}
+
+impl OrderBook {
+    fn from_secrets(secrets: &SecretStore) -> Self {
+        let path = secrets.get("DAY5_ORDER_BOOK_PATH").map(PathBuf::from);
+        let entries = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(json)) => serde_json::from_str::<Vec<BookEntry>>(&json).unwrap_or_else(|err| {
+                panic!("DAY5_ORDER_BOOK_PATH holds an invalid order book: {err}")
+            }),
+            Some(Err(err)) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Some(Err(err)) => panic!("DAY5_ORDER_BOOK_PATH can't be read: {err}"),
+            None => vec![],
+        };
+
+        let max_entries = secrets
+            .get("DAY5_ORDER_BOOK_MAX")
+            .map(|max| {
+                max.parse()
+                    .unwrap_or_else(|_| panic!("DAY5_ORDER_BOOK_MAX must be a number"))
+            })
+            .unwrap_or(10_000);
+
+        Self {
+            path: path.map(Arc::new),
+            max_entries,
+            changed: Arc::new(Notify::new()),
+            entries: Arc::new(Mutex::new(
+                entries
+                    .into_iter()
+                    .map(|entry| ((entry.name.clone(), entry.version.clone()), entry))
+                    .collect(),
+            )),
+        }
+    }
+
+    // saves the book whenever it changed, off the request path and outside the lock. Written next
+    // to the file and renamed over it so a crash never leaves half a file behind, a failed write
+    // is retried a second later.
+    fn spawn_writer(&self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let book = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                book.changed.notified().await;
+                let json = {
+                    let entries = book.entries.lock().unwrap();
+                    serde_json::to_string(&entries.values().collect::<Vec<_>>()).unwrap()
+                };
+
+                let path = path.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    let tmp = path.with_extension("tmp");
+                    fs::write(&tmp, json)?;
+                    fs::rename(tmp, path.as_path())
+                })
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err)));
+                if let Err(err) = written {
+                    eprintln!("failed to save the order book: {err}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    book.changed.notify_one();
+                }
+            }
+        });
+    }
+
+    // all or nothing, a batch that doesn't fit is left out of the book. Booking is a side effect of
+    // a submission, so a full book never fails the request.
+    fn record(&self, entries: impl IntoIterator<Item = BookEntry>) {
+        let entries = entries
+            .into_iter()
+            .map(|entry| ((entry.name.clone(), entry.version.clone()), entry))
+            .collect::<BTreeMap<_, _>>();
+        let mut book = self.entries.lock().unwrap();
+        let added = entries
+            .keys()
+            .filter(|key| !book.contains_key(*key))
+            .count();
+        if book.len() + added > self.max_entries {
+            eprintln!(
+                "order book is full, not booking {}",
+                entries
+                    .keys()
+                    .map(|(name, version)| format!("{name} {version}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return;
         }
+        book.extend(entries);
+        self.changed.notify_one();
+    }
+
+    // removes the entries of `name` matching `version`, or every version when it is `None`
+    fn remove(&self, name: &str, version: Option<&str>) -> usize {
+        let mut book = self.entries.lock().unwrap();
+        let before = book.len();
+        book.retain(|(entry_name, entry_version), _| {
+            entry_name != name || version.is_some_and(|version| entry_version != version)
+        });
+        let removed = before - book.len();
+        if removed > 0 {
+            self.changed.notify_one();
+        }
+        removed
+    }
+}
+
+fn book_entry<'a>(
+    package: &Package<Metadata>,
+    workspace: Option<&WorkspacePackage>,
+    orders: impl IntoIterator<Item = &'a (Section, Order)>,
+) -> BookEntry {
+    let version = inherit(package.version.clone(), workspace, |p| p.version.clone());
+    BookEntry {
+        name: package.name.clone(),
+        // cargo's default when a package doesn't set a version
+        version: version.unwrap_or_else(|| "0.0.0".to_string()),
+        orders: orders
+            .into_iter()
+            .filter_map(|(_, order)| {
+                let quantity = order.quantity().ok()?;
+                Some(BookedOrder {
+                    item: order.item.clone(),
+                    quantity,
+                })
+            })
+            .collect(),
+    }
+}
+
+#[derive(Serialize)]
+struct BookListing<'a> {
+    packages: Vec<&'a BookEntry>,
+}
+
+#[derive(Deserialize)]
+struct TotalsQuery {
+    name: Option<String>,
+}
+
+async fn list_book(State(state): State<Day5>, headers: HeaderMap) -> Response {
+    let book = state.book.entries.lock().unwrap();
+    render(
+        Format::from_accept(&headers),
+        &BookListing {
+            packages: book.values().collect(),
+        },
+    )
+}
+
+async fn book_totals(
+    State(state): State<Day5>,
+    Query(query): Query<TotalsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let book = state.book.entries.lock().unwrap();
+    let mut totals = BTreeMap::<&str, u64>::new();
+    let entries = book
+        .values()
+        .filter(|entry| query.name.as_ref().is_none_or(|name| &entry.name == name));
+    for order in entries.flat_map(|entry| &entry.orders) {
+        *totals.entry(&order.item).or_default() += u64::from(order.quantity);
+    }
+    render(Format::from_accept(&headers), &totals)
+}
+
+async fn get_book_entry(
+    State(state): State<Day5>,
+    Path((name, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let book = state.book.entries.lock().unwrap();
+    match book.get(&(name, version)) {
+        Some(entry) => render(Format::from_accept(&headers), entry),
+        None => (StatusCode::NOT_FOUND, "No orders booked for this package").into_response(),
+    }
+}
+
+async fn delete_book_entry(
+    State(state): State<Day5>,
+    headers: HeaderMap,
+    Path((name, version)): Path<(String, String)>,
+) -> Response {
+    if let Err(rejection) = admin::authorize(state.admin_token.as_deref(), &headers) {
+        return rejection.into_response();
+    }
+    match state.book.remove(&name, Some(&version)) {
+        0 => (StatusCode::NOT_FOUND, "No orders booked for this package").into_response(),
+        _ => StatusCode::NO_CONTENT.into_response(),
     }
 }
 
-async fn toml_orders(headers: HeaderMap, text: String) -> impl IntoResponse {
-    let manifest = match headers.get("content-type") {
-        Some(content_type) => {
-            match content_type.to_str() {
-                Ok("application/toml") => {
-                    if let Ok(manifest) = toml::from_str::<Manifest<Metadata>>(&text) {
-                        manifest
-                    } else {
-                        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
+async fn delete_book_package(
+    State(state): State<Day5>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(rejection) = admin::authorize(state.admin_token.as_deref(), &headers) {
+        return rejection.into_response();
+    }
+    match state.book.remove(&name, None) {
+        0 => (StatusCode::NOT_FOUND, "No orders booked for this package").into_response(),
+        _ => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: Version,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum LockStatus {
+    // a locked version satisfies the requirement
+    Locked,
+    // the package is locked, but at versions outside the requirement
+    Mismatch,
+    Missing,
+    Invalid,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct DependencyCheck {
+    name: String,
+    kind: DependencyKind,
+    section: Section,
+    requirement: Option<String>,
+    locked: Vec<String>,
+    status: LockStatus,
+}
+
+// multipart uploads always answer with the full report, plus the lockfile cross-check if one
+// was uploaded
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct UploadSummary<'a> {
+    #[serde(flatten)]
+    report: OrderReport<'a>,
+    dependencies: Option<Vec<DependencyCheck>>,
+}
+
+fn check_dependency(
+    name: &str,
+    dependency: &Dependency,
+    workspace_dependencies: Option<&DepsSet>,
+    locked: &BTreeMap<&str, Vec<&Version>>,
+) -> (Option<String>, Vec<String>, LockStatus) {
+    // `dep.workspace = true` takes the requirement declared in `[workspace.dependencies]`
+    let dependency = match dependency {
+        Dependency::Inherited(_) => workspace_dependencies.and_then(|deps| deps.get(name)),
+        dependency => Some(dependency),
+    };
+    let package = dependency
+        .and_then(|dependency| dependency.package())
+        .unwrap_or(name);
+    let requirement = dependency.and_then(|dependency| match dependency {
+        Dependency::Simple(version) => Some(version.clone()),
+        Dependency::Detailed(detail) => detail.version.clone(),
+        Dependency::Inherited(_) => None,
+    });
+    let versions = locked.get(package).cloned().unwrap_or_default();
+    let locked = versions
+        .iter()
+        .map(|version| version.to_string())
+        .collect::<Vec<_>>();
+
+    let status = if versions.is_empty() {
+        LockStatus::Missing
+    } else {
+        match requirement.as_deref().map(VersionReq::parse) {
+            // path and git dependencies without a version only need to be present
+            None => LockStatus::Locked,
+            Some(Err(_)) => LockStatus::Invalid,
+            Some(Ok(req)) if versions.iter().any(|version| req.matches(version)) => {
+                LockStatus::Locked
+            }
+            Some(Ok(_)) => LockStatus::Mismatch,
+        }
+    };
+    (requirement, locked, status)
+}
+
+fn check_dependencies(
+    section: &Section,
+    manifest: &OrderManifest,
+    workspace_dependencies: Option<&DepsSet>,
+    locked: &BTreeMap<&str, Vec<&Version>>,
+) -> Vec<DependencyCheck> {
+    let mut declared = vec![
+        (
+            section,
+            DependencyKind::Normal,
+            manifest.dependencies.as_ref(),
+        ),
+        (
+            section,
+            DependencyKind::Dev,
+            manifest.dev_dependencies.as_ref(),
+        ),
+        (
+            section,
+            DependencyKind::Build,
+            manifest.build_dependencies.as_ref(),
+        ),
+    ];
+    if let Some(workspace) = &manifest.workspace {
+        declared.push((
+            &Section::Workspace,
+            DependencyKind::Normal,
+            workspace.dependencies.as_ref(),
+        ));
+    }
+
+    declared
+        .into_iter()
+        .flat_map(|(section, kind, deps)| {
+            deps.into_iter()
+                .flatten()
+                .map(move |dep| (section, kind, dep))
+        })
+        .map(|(section, kind, (name, dependency))| {
+            let (requirement, locked, status) =
+                check_dependency(name, dependency, workspace_dependencies, locked);
+            DependencyCheck {
+                name: name.clone(),
+                kind,
+                section: section.clone(),
+                requirement,
+                locked,
+                status,
+            }
+        })
+        .collect()
+}
+
+// the format of an uploaded file, anything not explicitly JSON or YAML is read as TOML
+fn part_format(field: &multer::Field) -> Format {
+    match field
+        .content_type()
+        .and_then(|mime| Format::from_media_type(mime.essence_str()))
+    {
+        Some(format @ (Format::Json | Format::Yaml)) => format,
+        _ => Format::Toml,
+    }
+}
+
+// `multipart/form-data` with a `manifest` (the root Cargo.toml), an optional `lockfile`
+// (Cargo.lock) and any number of `member` manifests whose inherited values, keywords included,
+// resolve against the root workspace. Dependencies are only cross-checked with a lockfile.
+async fn upload_orders(
+    state: &Day5,
+    headers: &HeaderMap,
+    body: Bytes,
+    boundary: String,
+) -> Response {
+    let mut multipart =
+        multer::Multipart::new(stream::once(async { Ok::<_, Infallible>(body) }), boundary);
+    let mut manifest = None;
+    let mut lockfile = None;
+    let mut members = vec![];
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let format = part_format(&field);
+        let text = match field.text().await {
+            Ok(text) => text,
+            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        };
+
+        match name.as_str() {
+            "manifest" => match parse_document::<OrderManifest>(format, &text) {
+                Ok(parsed) => manifest = Some(parsed),
+                Err(err) => {
+                    return ManifestError {
+                        part: Some("manifest"),
+                        ..err
                     }
+                    .into_response()
                 }
-                Ok("application/json") => {
-                    if let Ok(manifest) = serde_json::from_str::<Manifest<Metadata>>(&text) {
-                        manifest
-                    } else {
-                        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
+            },
+            "member" => match parse_document::<OrderManifest>(format, &text) {
+                Ok(parsed) => members.push(parsed),
+                Err(err) => {
+                    return ManifestError {
+                        part: Some("member"),
+                        ..err
                     }
+                    .into_response()
                 }
-                Ok("application/yaml") => {
-                    if let Ok(manifest) = serde_yaml::from_str::<Manifest<Metadata>>(&text) {
-                        manifest
-                    } else {
-                        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
+            },
+            "lockfile" => match parse_document::<Lockfile>(Format::Toml, &text) {
+                Ok(parsed) => lockfile = Some(parsed),
+                Err(err) => {
+                    return ManifestError {
+                        error: "Invalid lockfile",
+                        part: Some("lockfile"),
+                        ..err
                     }
+                    .into_response()
                 }
-                _ => return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported content type".to_string())
+            },
+            _ => {
+                return (StatusCode::BAD_REQUEST, format!("Unexpected part `{name}`"))
+                    .into_response()
             }
         }
-        None => return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Content type not set".to_string()),
+    }
+
+    let Some(manifest) = manifest else {
+        return (StatusCode::BAD_REQUEST, "No manifest part found").into_response();
     };
+    if manifest.package.is_none() && manifest.workspace.is_none() {
+        return (StatusCode::BAD_REQUEST, "No package or workspace found").into_response();
+    }
 
-    let package = manifest.package.unwrap();
-    if !package
-        .keywords
-        .and_then(|k| k.as_local())
-        .map(|k| k.contains(&"Christmas 2024".to_string()))
-        .unwrap_or_default()
-    {
-        return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string());
+    let workspace_package = manifest
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.package.clone());
+    let workspace_dependencies = manifest
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.dependencies.clone());
+    if !has_magic_keyword(
+        &state.policy,
+        manifest.package.as_ref(),
+        workspace_package.as_ref(),
+    ) {
+        return (StatusCode::BAD_REQUEST, "Magic keyword not provided").into_response();
+    }
+
+    let locked = lockfile.as_ref().map(|lockfile| {
+        let mut locked = BTreeMap::<&str, Vec<&Version>>::new();
+        for package in &lockfile.package {
+            locked
+                .entry(package.name.as_str())
+                .or_default()
+                .push(&package.version);
+        }
+        locked
+    });
+
+    let mut dependencies = locked.as_ref().map(|locked| {
+        check_dependencies(
+            &Section::Package,
+            &manifest,
+            workspace_dependencies.as_ref(),
+            locked,
+        )
+    });
+    let mut member_orders = vec![];
+    let mut booked = vec![];
+    for member in members {
+        let Some(package) = &member.package else {
+            return (StatusCode::BAD_REQUEST, "Member manifest without a package").into_response();
+        };
+        if !has_magic_keyword(&state.policy, Some(package), workspace_package.as_ref()) {
+            let message = format!("Magic keyword not provided in member `{}`", package.name);
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+
+        let section = Section::Member(package.name.clone());
+        if let (Some(dependencies), Some(locked)) = (&mut dependencies, &locked) {
+            dependencies.extend(check_dependencies(
+                &section,
+                &member,
+                workspace_dependencies.as_ref(),
+                locked,
+            ));
+        }
+        let Some(mut package) = member.package else {
+            continue;
+        };
+        let orders = section_orders(section, package.metadata.take()).collect::<Vec<_>>();
+        booked.push(book_entry(&package, workspace_package.as_ref(), &orders));
+        member_orders.extend(orders);
     }
 
-    let metadata = match package.metadata {
-        Some(metadata) => metadata,
-        None => return (StatusCode::NO_CONTENT, "No metadata found".to_string()),
+    let mut package = manifest.package;
+    let mut orders = section_orders(
+        Section::Package,
+        package.as_mut().and_then(|package| package.metadata.take()),
+    )
+    .chain(section_orders(
+        Section::Workspace,
+        manifest.workspace.and_then(|workspace| workspace.metadata),
+    ))
+    .collect::<Vec<_>>();
+    if let Some(package) = &package {
+        booked.push(book_entry(package, workspace_package.as_ref(), &orders));
+    }
+    orders.extend(member_orders);
+    state.book.record(booked);
+
+    let summary = UploadSummary {
+        report: OrderReport::new(&orders),
+        dependencies,
     };
+    render(Format::from_accept(headers), &summary)
+}
 
-    let orders: String = metadata
-        .orders
-        .iter()
-        .filter(|order| order.quantity.is_some())
-        .map(|order| order.to_string())
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize)]
+struct Diagnostic {
+    severity: Severity,
+    // TOML key path of the offending entry, e.g. `dependencies.serde`
+    path: String,
+    message: String,
+}
+
+const KNOWN_KEYS: &[&str] = &[
+    "cargo-features",
+    "package",
+    "project",
+    "lib",
+    "bin",
+    "example",
+    "test",
+    "bench",
+    "dependencies",
+    "dev-dependencies",
+    "dev_dependencies",
+    "build-dependencies",
+    "build_dependencies",
+    "target",
+    "features",
+    "patch",
+    "replace",
+    "profile",
+    "workspace",
+    "badges",
+    "lints",
+];
+
+const DEPENDENCY_TABLES: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+
+// quotes keys that are not valid bare TOML keys, like `cfg(unix)` targets
+fn key_path(keys: &[&str]) -> String {
+    keys.iter()
+        .map(|key| {
+            let bare = !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if bare {
+                key.to_string()
+            } else {
+                basic_string(key)
+            }
+        })
         .collect::<Vec<_>>()
-        .join("\n");
+        .join(".")
+}
 
-    if orders.is_empty() {
-        return (StatusCode::NO_CONTENT, "No orders found".to_string());
+// a TOML basic string, only quotes, backslashes and control characters are escaped
+fn basic_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\u{8}' => quoted.push_str("\\b"),
+            '\t' => quoted.push_str("\\t"),
+            '\n' => quoted.push_str("\\n"),
+            '\u{c}' => quoted.push_str("\\f"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Serialize, Default)]
+struct Lints {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Lints {
+    fn push(&mut self, severity: Severity, path: &[&str], message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            path: key_path(path),
+            message: message.into(),
+        });
+    }
+
+    fn check_edition(&mut self, path: &[&str], edition: Option<&Value>) {
+        let Some(edition) = edition else { return };
+        if is_inherited(edition) {
+            return;
+        }
+        if serde_json::from_value::<Edition>(edition.clone()).is_err() {
+            self.push(Severity::Error, path, format!("invalid edition {edition}"));
+        }
+    }
+
+    fn check_package(&mut self, package: &Value) {
+        if package.get("license").is_none() && package.get("license-file").is_none() {
+            self.push(
+                Severity::Warning,
+                &["package", "license"],
+                "missing license or license-file",
+            );
+        }
+        if package.get("description").is_none() {
+            self.push(
+                Severity::Warning,
+                &["package", "description"],
+                "missing description",
+            );
+        }
+        self.check_edition(&["package", "edition"], package.get("edition"));
+    }
+
+    fn check_dependencies(&mut self, path: &[&str], deps: &Value) {
+        let Some(deps) = deps.as_object() else { return };
+        for (name, dep) in deps {
+            let version = match dep {
+                Value::String(version) => Some(version.as_str()),
+                dep => dep.get("version").and_then(Value::as_str),
+            };
+            if version.is_some_and(|version| version.trim() == "*") {
+                let path = [path, &[name.as_str()]].concat();
+                self.push(
+                    Severity::Warning,
+                    &path,
+                    format!("wildcard version for `{name}`"),
+                );
+            }
+        }
+    }
+
+    // a dependency that is also a dev-dependency in the same scope is redundant
+    fn check_duplicates(&mut self, path: &[&str], scope: &Value) {
+        let (Some(deps), Some(dev_deps)) = (
+            scope.get("dependencies").and_then(Value::as_object),
+            scope.get("dev-dependencies").and_then(Value::as_object),
+        ) else {
+            return;
+        };
+        for name in dev_deps.keys().filter(|name| deps.contains_key(*name)) {
+            let path = [path, &["dev-dependencies", name.as_str()]].concat();
+            self.push(
+                Severity::Warning,
+                &path,
+                format!("`{name}` is also declared in [dependencies]"),
+            );
+        }
+    }
+
+    fn check_scope(&mut self, path: &[&str], scope: &Value) {
+        for table in DEPENDENCY_TABLES {
+            if let Some(deps) = scope.get(*table) {
+                self.check_dependencies(&[path, &[*table]].concat(), deps);
+            }
+        }
+        self.check_duplicates(path, scope);
+    }
+
+    fn check_manifest(&mut self, manifest: &Value) {
+        let Some(table) = manifest.as_object() else {
+            self.push(Severity::Error, &[], "manifest is not a table");
+            return;
+        };
+        for key in table
+            .keys()
+            .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+        {
+            self.push(
+                Severity::Warning,
+                &[key.as_str()],
+                format!("unknown top-level key `{key}`"),
+            );
+        }
+
+        match (manifest.get("package"), manifest.get("workspace")) {
+            (None, None) => self.push(Severity::Error, &[], "no package or workspace found"),
+            (Some(package), _) => self.check_package(package),
+            _ => {}
+        }
+        if let Some(workspace) = manifest.get("workspace") {
+            let package = workspace.get("package");
+            self.check_edition(
+                &["workspace", "package", "edition"],
+                package.and_then(|p| p.get("edition")),
+            );
+            if let Some(deps) = workspace.get("dependencies") {
+                self.check_dependencies(&["workspace", "dependencies"], deps);
+            }
+        }
+
+        self.check_scope(&[], manifest);
+        if let Some(targets) = manifest.get("target").and_then(Value::as_object) {
+            for (target, scope) in targets {
+                self.check_scope(&["target", target.as_str()], scope);
+            }
+        }
     }
-    (StatusCode::OK, orders)
 }
 
-pub fn day5_routes(router: Router) -> Router {
-    router.route("/5/manifest", post(toml_orders))
-}
\ No newline at end of file
+fn is_inherited(value: &Value) -> bool {
+    value.get("workspace").and_then(Value::as_bool) == Some(true)
+}
+
+// the manifest is linted as an untyped document so problems that would fail typed parsing, like
+// an unknown edition, are reported instead of rejected
+async fn lint_manifest(headers: HeaderMap, body: String) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type.to_str().unwrap_or_default());
+    let manifest = match parse_manifest::<Value>(content_type, &body) {
+        Ok(manifest) => manifest,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let mut lints = Lints::default();
+    lints.check_manifest(&manifest);
+    lints
+        .diagnostics
+        .sort_by_key(|diagnostic| diagnostic.severity);
+    render(Format::from_accept(&headers), &lints)
+}
+
+// TOML datetimes come out of `toml` as a private marker table, other formats get the plain string
+fn unwrap_toml_datetimes(value: &mut YamlValue) {
+    match value {
+        YamlValue::Mapping(map) => match map.get("$__toml_private_datetime") {
+            Some(YamlValue::String(datetime)) if map.len() == 1 => {
+                *value = YamlValue::String(datetime.clone())
+            }
+            _ => map.values_mut().for_each(unwrap_toml_datetimes),
+        },
+        YamlValue::Sequence(values) => values.iter_mut().for_each(unwrap_toml_datetimes),
+        _ => {}
+    }
+}
+
+// the manifest is only validated against `Manifest`, the untyped document is what gets converted
+// so unknown keys and metadata survive the round-trip. It's held as a YAML value because YAML
+// mappings keep their key order, unlike `serde_json`'s map without `preserve_order`.
+async fn convert_manifest(headers: HeaderMap, body: String) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type.to_str().unwrap_or_default());
+    let mut manifest = match parse_manifest::<YamlValue>(content_type, &body) {
+        Ok(manifest) => manifest,
+        Err(rejection) => return rejection.into_response(),
+    };
+    unwrap_toml_datetimes(&mut manifest);
+    if let Err(err) = serde_path_to_error::deserialize::<_, OrderManifest>(manifest.clone()) {
+        let message = err.inner().to_string();
+        return ManifestError::invalid(err, message, None).into_response();
+    }
+
+    let format = Format::from_accept(&headers);
+    if format == Format::Text {
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            "Accept a JSON, YAML or TOML manifest",
+        )
+            .into_response();
+    }
+    // same format in and out, hand the document back untouched to keep comments and layout
+    if content_type.and_then(Format::from_content_type) == Some(format) {
+        return (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, format.content_type())],
+            body,
+        )
+            .into_response();
+    }
+
+    let body = match format {
+        Format::Json => serde_json::to_string_pretty(&manifest).map_err(|err| err.to_string()),
+        Format::Yaml => serde_yaml::to_string(&manifest).map_err(|err| err.to_string()),
+        // TOML has no null, so documents holding one can't be converted
+        _ => toml::to_string(&manifest).map_err(|err| err.to_string()),
+    };
+    match body {
+        Ok(body) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, format.content_type())],
+            body,
+        )
+            .into_response(),
+        Err(err) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Manifest can't be converted: {err}"),
+        )
+            .into_response(),
+    }
+}
+
+pub fn day5_routes(router: Router, secrets: &SecretStore) -> Router {
+    let state = Day5 {
+        policy: Arc::new(KeywordPolicy::from_secrets(secrets)),
+        book: OrderBook::from_secrets(secrets),
+        admin_token: secrets
+            .get("DAY5_ADMIN_TOKEN")
+            .filter(|token| !token.is_empty())
+            .map(Arc::from),
+    };
+    state.book.spawn_writer();
+
+    router.merge(
+        Router::new()
+            .route("/5/manifest", post(toml_orders))
+            .route("/5/lint", post(lint_manifest))
+            .route("/5/convert", post(convert_manifest))
+            .route("/5/orders", get(list_book))
+            .route("/5/orders/totals", get(book_totals))
+            .route("/5/orders/:name", delete(delete_book_package))
+            .route(
+                "/5/orders/:name/:version",
+                get(get_book_entry).delete(delete_book_entry),
+            )
+            .with_state(state)
+            // `Content-Encoding: gzip` and `br` bodies, for manifests and multipart uploads alike
+            .layer(RequestDecompressionLayer::new()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantity(format: Format, quantity: Option<&str>) -> Result<u32, RejectReason> {
+        let text = match (format, quantity) {
+            (Format::Json, Some(quantity)) => format!(r#"{{"item": "x", "quantity": {quantity}}}"#),
+            (Format::Json, None) => r#"{"item": "x"}"#.to_string(),
+            (Format::Toml, Some(quantity)) => format!("item = \"x\"\nquantity = {quantity}"),
+            (Format::Toml, None) => "item = \"x\"".to_string(),
+            (_, Some(quantity)) => format!("item: x\nquantity: {quantity}"),
+            (_, None) => "item: x".to_string(),
+        };
+        parse_document::<Order>(format, &text)
+            .unwrap_or_else(|_| panic!("{} order doesn't parse: {text}", format.name()))
+            .quantity()
+    }
+
+    #[test]
+    fn order_quantities() {
+        use RejectReason::*;
+
+        let cases = [
+            (None, Err(MissingQuantity)),
+            (Some("3"), Ok(3)),
+            (Some("0"), Ok(0)),
+            (Some("4294967295"), Ok(u32::MAX)),
+            (Some("4294967296"), Err(Overflow)),
+            (Some("9223372036854775807"), Err(Overflow)),
+            (Some("-1"), Err(Negative)),
+            (Some("-9223372036854775808"), Err(Negative)),
+            (Some("1.5"), Err(NonInteger)),
+            (Some("2.0"), Err(NonInteger)),
+            (Some("-0.5"), Err(NonInteger)),
+            // integers too large for 64 bits written as floats
+            (Some("1e20"), Err(Overflow)),
+            (Some("-1e20"), Err(Negative)),
+            (Some("1e300"), Err(Overflow)),
+            (Some("\"3\""), Err(InvalidType)),
+            (Some("true"), Err(InvalidType)),
+            (Some("[1]"), Err(InvalidType)),
+        ];
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            for (literal, expected) in cases {
+                assert_eq!(
+                    quantity(format, literal),
+                    expected,
+                    "{literal:?} in {}",
+                    format.name()
+                );
+            }
+        }
+
+        // TOML has neither null nor integers beyond 64 bits
+        for format in [Format::Json, Format::Yaml] {
+            let null = if format == Format::Json { "null" } else { "~" };
+            assert_eq!(quantity(format, Some(null)), Err(MissingQuantity));
+            assert_eq!(
+                quantity(format, Some("18446744073709551616")),
+                Err(Overflow)
+            );
+            assert_eq!(
+                quantity(format, Some("-18446744073709551616")),
+                Err(Negative)
+            );
+        }
+    }
+
+    #[test]
+    fn manifest_error_locations() {
+        let untagged = "data did not match any variant of untagged enum MaybeInherited";
+        let item = "invalid type: sequence, expected a string";
+        let item_path = Some("package.metadata.orders[0].item");
+        let cases = [
+            (
+                Format::Toml,
+                "[package]\nname = \"a\"\nedition = \"1999\"\n",
+                untagged,
+                Some((3, 11)),
+                Some("package.edition"),
+            ),
+            (
+                Format::Json,
+                "{\"package\": {\"name\": \"a\",\n  \"edition\": \"1999\"}}",
+                untagged,
+                Some((2, 20)),
+                Some("package.edition"),
+            ),
+            (
+                Format::Yaml,
+                "package:\n  name: a\n  edition: \"1999\"\n",
+                untagged,
+                Some((2, 3)),
+                Some("package.edition"),
+            ),
+            (
+                Format::Toml,
+                "[package]\nversion = \"1.0.0\"\n",
+                "missing field `name`",
+                Some((1, 1)),
+                Some("package.name"),
+            ),
+            (
+                Format::Json,
+                "{\"package\": {\n  \"version\": \"1.0.0\"}}",
+                "missing field `name`",
+                Some((2, 21)),
+                Some("package.name"),
+            ),
+            (
+                Format::Yaml,
+                "package:\n  version: 1.0.0\n",
+                "missing field `name`",
+                Some((2, 3)),
+                Some("package.name"),
+            ),
+            (
+                Format::Toml,
+                "[package]\nname = \"a\"\n\n[[package.metadata.orders]]\nitem = [1]\n",
+                item,
+                Some((5, 8)),
+                item_path,
+            ),
+            (
+                Format::Json,
+                "{\"package\": {\"name\": \"a\", \"metadata\": {\"orders\": [\n  {\"item\": [1]}]}}}",
+                item,
+                Some((2, 11)),
+                item_path,
+            ),
+            (
+                Format::Yaml,
+                "package:\n  name: a\n  metadata:\n    orders:\n      - item: [1]\n",
+                item,
+                Some((5, 15)),
+                item_path,
+            ),
+            (
+                Format::Toml,
+                "[package\n",
+                "invalid table header\nexpected `.`, `]`",
+                Some((1, 9)),
+                None,
+            ),
+            (
+                Format::Json,
+                "{\"package\": ",
+                "EOF while parsing a value",
+                Some((1, 12)),
+                Some("package"),
+            ),
+        ];
+        for (format, text, message, location, path) in cases {
+            let err = parse_document::<OrderManifest>(format, text).err().unwrap();
+            let context = format!("{} document {text:?}", format.name());
+            assert_eq!(err.message.as_deref(), Some(message), "{context}");
+            assert_eq!(err.line.zip(err.column), location, "{context}");
+            assert_eq!(err.path.as_deref(), path, "{context}");
+        }
+    }
+
+    #[test]
+    fn locations_and_stripping() {
+        let text = "ab\ncdé\nf";
+        assert_eq!(location(text, 0), (1, 1));
+        assert_eq!(location(text, 2), (1, 3));
+        assert_eq!(location(text, 3), (2, 1));
+        // columns count characters, not bytes
+        assert_eq!(location(text, text.find('f').unwrap()), (3, 1));
+        assert_eq!(location(text, text.len() - 2), (2, 4));
+        assert_eq!(location(text, 100), (3, 2));
+
+        let message = "expected value at line 2 column 5".to_string();
+        assert_eq!(strip_location(message.clone(), 2, 5), "expected value");
+        assert_eq!(strip_location(message.clone(), 2, 6), message);
+
+        let path = |json: &str| {
+            serde_path_to_error::deserialize::<_, BTreeMap<String, Vec<BTreeMap<String, u32>>>>(
+                &mut serde_json::Deserializer::from_str(json),
+            )
+            .err()
+            .unwrap()
+            .path()
+            .clone()
+        };
+        let path = path(r#"{"orders": [{"item": "x"}]}"#);
+        assert_eq!(path.to_string(), "orders[0].item");
+        let strip = |message: &str| strip_path(message.to_string(), &path);
+        assert_eq!(strip("orders[0].item: invalid type"), "invalid type");
+        assert_eq!(strip("orders[0]: invalid type"), "invalid type");
+        assert_eq!(strip("orders: invalid type"), "invalid type");
+        assert_eq!(strip("invalid type: string"), "invalid type: string");
+        assert_eq!(strip("order: invalid type"), "order: invalid type");
+    }
+
+    fn book(max_entries: usize) -> OrderBook {
+        OrderBook {
+            path: None,
+            max_entries,
+            entries: Arc::default(),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    fn entry(name: &str, version: &str) -> BookEntry {
+        BookEntry {
+            name: name.to_string(),
+            version: version.to_string(),
+            orders: vec![],
+        }
+    }
+
+    fn booked(book: &OrderBook) -> Vec<(String, String)> {
+        book.entries.lock().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn full_book_skips_new_entries() {
+        let book = book(2);
+        book.record([entry("a", "1.0.0")]);
+        book.record([entry("b", "1.0.0"), entry("c", "1.0.0")]);
+        assert_eq!(booked(&book), [("a".into(), "1.0.0".into())]);
+
+        book.record([entry("b", "1.0.0")]);
+        book.record([entry("c", "1.0.0")]);
+        // resubmissions replace their entry even when the book is full
+        book.record([entry("a", "1.0.0")]);
+        assert_eq!(
+            booked(&book),
+            [("a".into(), "1.0.0".into()), ("b".into(), "1.0.0".into())]
+        );
+
+        assert_eq!(book.remove("a", Some("2.0.0")), 0);
+        assert_eq!(book.remove("a", None), 1);
+        book.record([entry("c", "1.0.0")]);
+        assert_eq!(book.entries.lock().unwrap().len(), 2);
+    }
+}
@@ -1,152 +1,1863 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-
-use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::post;
-use axum::Router;
-use leaky_bucket::RateLimiter;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, io};
+
+use crate::admin;
+use crate::format::{render, Format};
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::http::{header, HeaderMap, HeaderName, StatusCode};
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use axum_extra::extract::CookieJar;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
+use shuttle_runtime::SecretStore;
+use tokio::sync::Notify;
 
+// what identifies a client, each one gets its own bucket unless this is `Global`
+#[derive(Debug, Clone)]
+enum ClientKey {
+    Global,
+    // the address from `forwarded_for`
+    Ip,
+    Header(HeaderName),
+    Cookie(String),
+}
+
+impl ClientKey {
+    fn from_secrets(secrets: &SecretStore) -> Self {
+        match secrets.get("DAY9_CLIENT_KEY").as_deref() {
+            None | Some("global") => ClientKey::Global,
+            Some("ip") => ClientKey::Ip,
+            Some("header") => {
+                let name = secrets
+                    .get("DAY9_CLIENT_HEADER")
+                    .unwrap_or("x-api-key".to_string());
+                ClientKey::Header(
+                    HeaderName::try_from(name)
+                        .unwrap_or_else(|_| panic!("DAY9_CLIENT_HEADER must be a header name")),
+                )
+            }
+            Some("cookie") => ClientKey::Cookie(
+                secrets
+                    .get("DAY9_CLIENT_COOKIE")
+                    .unwrap_or("client".to_string()),
+            ),
+            Some(_) => panic!("DAY9_CLIENT_KEY must be global, ip, header or cookie"),
+        }
+    }
+
+    // requests without the key share one anonymous bucket
+    fn client(&self, headers: &HeaderMap, address: Option<&str>) -> String {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let client = match self {
+            ClientKey::Global => None,
+            ClientKey::Ip => address.map(str::to_string),
+            ClientKey::Header(name) => header(name.as_str()).map(str::to_string),
+            ClientKey::Cookie(name) => CookieJar::from_headers(headers)
+                .get(name)
+                .map(|cookie| cookie.value().to_string()),
+        };
+        client
+            .filter(|client| !client.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+// the address the nearest of `proxies` trusted proxies appended to `X-Forwarded-For`. the service
+// never sees the peer itself, only the proxy in front of it, and every hop left of the trusted
+// ones is whatever the client sent
+fn forwarded_for(headers: &HeaderMap, proxies: usize) -> Option<String> {
+    // every proxy appends to its own header line or to the end of the last one
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|forwarded| forwarded.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    // with fewer hops than trusted proxies all of them were appended by one
+    hops.len()
+        .checked_sub(proxies)
+        .map_or(hops.first(), |hop| hops.get(hop))
+        .filter(|address| !address.is_empty())
+        .map(|address| address.to_string())
+}
+
+// who sent a request, resolved once per request: `key` is what its tokens are kept under, empty
+// when they're shared, and `address` where it connected from whatever the key strategy
+#[derive(Debug)]
+struct Client {
+    key: String,
+    address: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BucketConfig {
+    initial: usize,
+    max: usize,
+    refill: usize,
+    interval_ms: u64,
+    // tokens a single unit costs, `take?n=3` takes `3 * cost`
+    cost: usize,
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            initial: 5,
+            max: 5,
+            refill: 1,
+            interval_ms: 1000,
+            cost: 1,
+        }
+    }
+}
+
+impl BucketConfig {
+    fn from_secrets(secrets: &SecretStore) -> Self {
+        let default = Self::default();
+        let number = |key: &str, default: u64, min: u64| match secrets.get(key) {
+            Some(value) => value
+                .parse::<u64>()
+                .ok()
+                .filter(|value| *value >= min)
+                .unwrap_or_else(|| panic!("{key} must be a number of at least {min}")),
+            None => default,
+        };
+
+        let config = Self {
+            initial: number("DAY9_BUCKET_INITIAL", default.initial as u64, 0) as usize,
+            max: number("DAY9_BUCKET_MAX", default.max as u64, 1) as usize,
+            refill: number("DAY9_BUCKET_REFILL", default.refill as u64, 1) as usize,
+            interval_ms: number("DAY9_BUCKET_INTERVAL_MS", default.interval_ms, 1),
+            cost: number("DAY9_BUCKET_COST", default.cost as u64, 1) as usize,
+        };
+        Self {
+            initial: config.initial.min(config.max),
+            ..config
+        }
+    }
+
+    // tokens a bucket holding `tokens` has after `elapsed`, counting only whole intervals
+    fn refilled(&self, tokens: usize, elapsed: Duration) -> usize {
+        tokens
+            .saturating_add(self.refill.saturating_mul(self.intervals(elapsed)))
+            .min(self.max)
+    }
+
+    fn intervals(&self, elapsed: Duration) -> usize {
+        let intervals = elapsed.as_millis() / u128::from(self.interval_ms);
+        usize::try_from(intervals).unwrap_or(usize::MAX)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+// a client's tokens as of `refilled_at`, the last refill counted. refills are added lazily
+// whenever the bucket is looked at, so the count is always current
+#[derive(Debug, Clone, Copy)]
+struct ClientTokens {
+    tokens: usize,
+    refilled_at: Instant,
+    used: Instant,
+    // the latest deadline of a request queueing for these tokens
+    waiting_until: Option<Instant>,
+}
+
+impl ClientTokens {
+    fn new(tokens: usize, now: Instant) -> Self {
+        Self {
+            tokens,
+            refilled_at: now,
+            used: now,
+            waiting_until: None,
+        }
+    }
+
+    // adds the refills due by `now`, the progress into the current interval is kept unless the
+    // bucket is full, a full bucket starts counting again from `now`
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = config.refilled(self.tokens.min(config.max), elapsed);
+        self.refilled_at = if self.tokens == config.max {
+            now
+        } else {
+            let intervals = u32::try_from(config.intervals(elapsed)).unwrap_or(u32::MAX);
+            config
+                .interval()
+                .checked_mul(intervals)
+                .and_then(|counted| self.refilled_at.checked_add(counted))
+                .unwrap_or(now)
+        };
+    }
+}
+
+// one bucket per client, the least recently used is evicted once `max_clients` are tracked, an
+// evicted client simply starts over with a fresh bucket. clients with a request queueing are only
+// evicted when every client has one, so a waiting request doesn't end up taking from a fresh
+// bucket. every change happens in place under the lock around the map, so refills and
+// reconfiguration apply to requests already waiting too
+#[derive(Debug)]
+struct ClientBuckets {
+    config: BucketConfig,
+    max_clients: usize,
+    buckets: HashMap<String, ClientTokens>,
+    // the client that created the bucket, `None` for milk and buckets set up by an admin
+    creator: Option<String>,
+}
+
+impl ClientBuckets {
+    // the client's tokens as of `now`, starting to track it if it's new
+    fn get(&mut self, client: &str, now: Instant) -> &mut ClientTokens {
+        if !self.buckets.contains_key(client) && self.buckets.len() >= self.max_clients {
+            let idle = self
+                .buckets
+                .iter()
+                .min_by_key(|(_, tokens)| {
+                    (
+                        tokens.waiting_until.is_some_and(|until| until > now),
+                        tokens.used,
+                    )
+                })
+                .map(|(client, _)| client.clone());
+            if let Some(idle) = idle {
+                self.buckets.remove(&idle);
+            }
+        }
+
+        let config = self.config;
+        let tokens = self
+            .buckets
+            .entry(client.to_string())
+            .or_insert_with(|| ClientTokens::new(config.initial, now));
+        tokens.refill(&config, now);
+        tokens.used = now;
+        tokens
+    }
+
+    // tokens `client` holds without tracking it, an unknown client would start out with `initial`
+    fn peek(&self, client: &str, now: Instant) -> ClientTokens {
+        match self.buckets.get(client) {
+            Some(tokens) => {
+                let mut tokens = *tokens;
+                tokens.refill(&self.config, now);
+                tokens
+            }
+            None => ClientTokens::new(self.config.initial, now),
+        }
+    }
+
+    fn refill(&mut self, client: &str, amount: Option<usize>, now: Instant) {
+        let config = self.config;
+        let tokens = self.get(client, now);
+        tokens.tokens = match amount {
+            Some(amount) => tokens.tokens.saturating_add(amount).min(config.max),
+            None => config.max,
+        };
+        tokens.refill(&config, now);
+    }
+
+    fn restore(
+        config: BucketConfig,
+        max_clients: usize,
+        saved: &SavedBucket,
+        elapsed: Duration,
+    ) -> Self {
+        let now = Instant::now();
+        let buckets = saved
+            .clients
+            .iter()
+            .take(max_clients)
+            .map(|(client, tokens)| {
+                let tokens = ClientTokens::new(config.refilled(*tokens, elapsed), now);
+                (client.clone(), tokens)
+            })
+            .collect();
+        Self {
+            config,
+            max_clients,
+            buckets,
+            creator: saved.creator.clone(),
+        }
+    }
+
+    fn save(&self) -> SavedBucket {
+        SavedBucket {
+            config: self.config,
+            clients: self.balances(),
+            creator: self.creator.clone(),
+        }
+    }
+
+    // current tokens of every tracked client
+    fn balances(&self) -> BTreeMap<String, usize> {
+        let now = Instant::now();
+        self.buckets
+            .keys()
+            .map(|client| (client.clone(), self.peek(client, now).tokens))
+            .collect()
+    }
+
+    // every bucket keeps the tokens it has by now, capped at the new maximum. progress into the
+    // current interval was made at the old rate, so the next refill is a whole new interval away
+    fn reconfigure(&mut self, config: BucketConfig, now: Instant) {
+        for tokens in self.buckets.values_mut() {
+            tokens.refill(&self.config, now);
+            tokens.tokens = tokens.tokens.min(config.max);
+            tokens.refilled_at = now;
+        }
+        self.config = config;
+    }
+}
+
+// the bucket behind `/9/milk`, which always exists and can't be deleted
+const MILK: &str = "milk";
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Withdrawn,
+    Rejected,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Debug)]
+struct LedgerEntry {
+    timestamp_ms: u64,
+    // the key the client was limited by, left out for the shared bucket
+    client: Option<String>,
+    address: Option<String>,
+    outcome: Outcome,
+}
+
+#[derive(Serialize, Default, Clone, Copy, Debug)]
+struct MinuteStats {
+    withdrawn: u64,
+    rejected: u64,
+}
+
+// every `/9/milk` call, the latest `capacity` individually and per minute for the last `minutes`
+#[derive(Debug)]
+struct Ledger {
+    entries: VecDeque<LedgerEntry>,
+    capacity: usize,
+    withdrawn: u64,
+    rejected: u64,
+    // keyed by the minute's start in seconds since the epoch
+    per_minute: BTreeMap<u64, MinuteStats>,
+    minutes: u64,
+}
+
+impl Ledger {
+    fn record(&mut self, client: &Client, outcome: Outcome) {
+        let timestamp_ms = now_ms();
+        let minute = timestamp_ms / 60_000 * 60;
+        let stats = self.per_minute.entry(minute).or_default();
+        match outcome {
+            Outcome::Withdrawn => {
+                self.withdrawn += 1;
+                stats.withdrawn += 1;
+            }
+            Outcome::Rejected => {
+                self.rejected += 1;
+                stats.rejected += 1;
+            }
+        }
+        let oldest = minute.saturating_sub((self.minutes - 1) * 60);
+        self.per_minute.retain(|minute, _| *minute >= oldest);
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LedgerEntry {
+            timestamp_ms,
+            client: Some(client.key.clone()).filter(|key| !key.is_empty()),
+            address: client.address.clone(),
+            outcome,
+        });
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize)]
+struct SavedBucket {
+    config: BucketConfig,
+    clients: BTreeMap<String, usize>,
+    #[serde(default)]
+    creator: Option<String>,
+}
+
+// every bucket's tokens per client as of `saved_at_ms` since the epoch, restored on startup with
+// the refills that would have happened in between. progress into an interval isn't saved, the
+// time of saving stands in for the last refill
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    saved_at_ms: u64,
+    buckets: BTreeMap<String, SavedBucket>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+// every named bucket, each keeping its own per-client tokens
 #[derive(Debug, Clone)]
 pub struct Milk {
-    limiter: Arc<Mutex<RateLimiter>>,
+    key: ClientKey,
+    trusted_proxies: usize,
+    buckets: Arc<Mutex<HashMap<String, ClientBuckets>>>,
+    max_clients: usize,
+    max_buckets: usize,
+    // named buckets a single client may create, admins aren't limited
+    max_created: usize,
+    max_wait: Duration,
+    // where bucket state is saved to, see `SavedState`
+    state_path: Option<Arc<PathBuf>>,
+    save_interval: Duration,
+    ledger: Arc<Mutex<Ledger>>,
+    // woken whenever tokens are added other than by time passing, see `take`
+    changed: Arc<Notify>,
+    // bearer token for the `/9/admin` routes, which are disabled without one
+    admin_token: Option<Arc<str>>,
 }
 
 impl Milk {
-    fn new() -> Self {
+    fn new(secrets: &SecretStore) -> Self {
+        let limit = |key: &str, default: usize| {
+            secrets
+                .get(key)
+                .map(|max| {
+                    max.parse::<usize>()
+                        .ok()
+                        .filter(|max| *max > 0)
+                        .unwrap_or_else(|| panic!("{key} must be a positive number"))
+                })
+                .unwrap_or(default)
+        };
+        let max_clients = limit("DAY9_MAX_CLIENTS", 1024);
+        let state_path = secrets.get("DAY9_STATE_PATH").map(PathBuf::from);
+        let saved =
+            match state_path.as_ref().map(fs::read_to_string) {
+                Some(Ok(json)) => Some(serde_json::from_str::<SavedState>(&json).unwrap_or_else(
+                    |err| panic!("DAY9_STATE_PATH holds invalid bucket state: {err}"),
+                )),
+                Some(Err(err)) if err.kind() == io::ErrorKind::NotFound => None,
+                Some(Err(err)) => panic!("DAY9_STATE_PATH can't be read: {err}"),
+                None => None,
+            };
+
+        // milk always takes its settings from the secrets, named buckets keep the ones they were
+        // saved with
+        let milk_config = BucketConfig::from_secrets(secrets);
+        let mut buckets = HashMap::new();
+        if let Some(saved) = saved {
+            let elapsed = Duration::from_millis(now_ms().saturating_sub(saved.saved_at_ms));
+            for (name, bucket) in &saved.buckets {
+                let config = if name == MILK {
+                    milk_config
+                } else {
+                    bucket.config
+                };
+                buckets.insert(
+                    name.clone(),
+                    ClientBuckets::restore(config, max_clients, bucket, elapsed),
+                );
+            }
+        }
+        buckets
+            .entry(MILK.to_string())
+            .or_insert_with(|| ClientBuckets {
+                config: milk_config,
+                max_clients,
+                buckets: HashMap::new(),
+                creator: None,
+            });
+
         Self {
-            limiter: Arc::new(Mutex::new(new_limiter())),
+            key: ClientKey::from_secrets(secrets),
+            trusted_proxies: limit("DAY9_TRUSTED_PROXIES", 1),
+            buckets: Arc::new(Mutex::new(buckets)),
+            max_clients,
+            max_buckets: limit("DAY9_MAX_BUCKETS", 64),
+            max_created: limit("DAY9_BUCKETS_PER_CLIENT", 4),
+            max_wait: Duration::from_millis(limit("DAY9_MAX_WAIT_MS", 30_000) as u64),
+            state_path: state_path.map(Arc::new),
+            save_interval: Duration::from_millis(limit("DAY9_STATE_SAVE_MS", 1000) as u64),
+            ledger: Arc::new(Mutex::new(Ledger {
+                entries: VecDeque::new(),
+                capacity: limit("DAY9_LEDGER_SIZE", 10_000),
+                withdrawn: 0,
+                rejected: 0,
+                per_minute: BTreeMap::new(),
+                minutes: limit("DAY9_STATS_MINUTES", 60) as u64,
+            })),
+            changed: Arc::new(Notify::new()),
+            admin_token: secrets
+                .get("DAY9_ADMIN_TOKEN")
+                .filter(|token| !token.is_empty())
+                .map(Arc::from),
+        }
+    }
+
+    fn save(&self) -> SavedState {
+        let buckets = self.buckets.lock().unwrap();
+        SavedState {
+            saved_at_ms: now_ms(),
+            buckets: buckets
+                .iter()
+                .map(|(name, bucket)| (name.clone(), bucket.save()))
+                .collect(),
+        }
+    }
+
+    // saves the bucket state every `save_interval` when it changed, written next to the file and
+    // renamed over it so a crash never leaves half a file behind
+    fn spawn_saver(&self) {
+        let Some(path) = self.state_path.clone() else {
+            return;
+        };
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let mut saved = BTreeMap::new();
+            let mut interval = tokio::time::interval(state.save_interval);
+            loop {
+                interval.tick().await;
+                let current = state.save();
+                let balances = current
+                    .buckets
+                    .iter()
+                    .map(|(name, bucket)| (name.clone(), serde_json::to_string(bucket).unwrap()))
+                    .collect::<BTreeMap<_, _>>();
+                if balances == saved {
+                    continue;
+                }
+
+                let path = path.clone();
+                let json = serde_json::to_string(&current).unwrap();
+                let written = tokio::task::spawn_blocking(move || {
+                    let tmp = path.with_extension("tmp");
+                    fs::write(&tmp, json)?;
+                    fs::rename(tmp, path.as_path())
+                })
+                .await;
+                if let Ok(Ok(())) = written {
+                    saved = balances;
+                }
+            }
+        });
+    }
+
+    fn client(&self, headers: &HeaderMap) -> Client {
+        let address = forwarded_for(headers, self.trusted_proxies);
+        Client {
+            key: self.key.client(headers, address.as_deref()),
+            address,
+        }
+    }
+
+    // takes `units * cost` of the client's tokens in bucket `name` if it has them, otherwise
+    // reports how long until it will
+    fn try_take(
+        &self,
+        name: &str,
+        client: &str,
+        units: usize,
+        deadline: Option<Instant>,
+    ) -> Result<(bool, RateLimitHeaders, Duration), (StatusCode, String)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let Some(bucket) = buckets.get_mut(name) else {
+            return Err(no_bucket(name));
+        };
+
+        let config = bucket.config;
+        let needed = units.saturating_mul(config.cost);
+        if units == 0 || needed > config.max {
+            let message = format!(
+                "Can take between 1 and {} {name} at once",
+                config.max / config.cost
+            );
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+
+        let now = Instant::now();
+        let tokens = bucket.get(client, now);
+        let taken = tokens.tokens >= needed;
+        if taken {
+            tokens.tokens -= needed;
+        } else if deadline.is_some() {
+            tokens.waiting_until = tokens.waiting_until.max(deadline);
+        }
+        let missing = (!taken).then_some(needed);
+        Ok((
+            taken,
+            rate_limit_headers(&config, tokens, missing),
+            time_until(&config, tokens, needed, now),
+        ))
+    }
+
+    // takes `units * cost` tokens, queueing for up to `wait` when they aren't available right
+    // away. the bucket is looked up again once the tokens are due or the buckets changed, so a
+    // refill, reconfiguration or deletion in the meantime applies to the waiting request as well
+    async fn take(
+        &self,
+        name: &str,
+        client: &Client,
+        units: usize,
+        wait: Option<Duration>,
+    ) -> Result<(bool, RateLimitHeaders), (StatusCode, String)> {
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        loop {
+            // registered before looking, so a change right after can't be missed
+            let changed = self.changed.notified();
+            let (taken, rate_limit, due_in) = self.try_take(name, &client.key, units, deadline)?;
+            match deadline {
+                Some(deadline) if !taken && Instant::now() < deadline => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    tokio::select! {
+                        _ = tokio::time::sleep(due_in.min(remaining)) => {}
+                        _ = changed => {}
+                    }
+                }
+                _ => return Ok((taken, rate_limit)),
+            }
+        }
+    }
+}
+
+// how long a request may queue, from `?wait=` or a `Prefer: wait=` header (RFC 7240) in seconds,
+// capped at `max`
+fn wait_time(
+    headers: &HeaderMap,
+    query: Option<f64>,
+    max: Duration,
+) -> Result<Option<Duration>, (StatusCode, String)> {
+    let preferred = headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split([',', ';']))
+        .find_map(|preference| {
+            let (name, value) = preference.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("wait")
+                .then(|| value.trim().trim_matches('"').parse::<f64>().ok())
+        });
+
+    let seconds = match (query, preferred) {
+        (Some(seconds), _) | (None, Some(Some(seconds))) => seconds,
+        (None, Some(None)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Invalid wait preference".to_string(),
+            ))
+        }
+        (None, None) => return Ok(None),
+    };
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(wait) => Ok(Some(wait.min(max))),
+        Err(_) => Err((
+            StatusCode::BAD_REQUEST,
+            "wait must be a non-negative number of seconds".to_string(),
+        )),
+    }
+}
+
+fn no_bucket(name: &str) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("No bucket named `{name}`"))
+}
+
+// milk is only taken and refilled through its own routes, so every withdrawal is in the ledger
+fn milk_route(route: &str) -> (StatusCode, String) {
+    let message = format!("The milk bucket is only available through {route}");
+    (StatusCode::BAD_REQUEST, message)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dimension {
+    Volume,
+    Mass,
+    Temperature,
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dimension::Volume => write!(f, "volume"),
+            Dimension::Mass => write!(f, "mass"),
+            Dimension::Temperature => write!(f, "temperature"),
         }
     }
 }
 
+// `base = value * scale + offset`, in litres, kilograms or kelvin. plain `gallons` are US gallons
+// and plain `pints` imperial pints, as the legacy request format always had them
+struct Unit {
+    names: &'static [&'static str],
+    dimension: Dimension,
+    scale: f64,
+    offset: f64,
+}
+
+const fn unit(names: &'static [&'static str], dimension: Dimension, scale: f64) -> Unit {
+    Unit {
+        names,
+        dimension,
+        scale,
+        offset: 0.0,
+    }
+}
+
+const UNITS: &[Unit] = &[
+    unit(
+        &["liters", "litres", "liter", "litre", "l"],
+        Dimension::Volume,
+        1.0,
+    ),
+    unit(
+        &["milliliters", "millilitres", "ml"],
+        Dimension::Volume,
+        0.001,
+    ),
+    unit(
+        &["cubic_meters", "cubic_metres", "m3"],
+        Dimension::Volume,
+        1000.0,
+    ),
+    unit(
+        &["gallons", "gallon", "us_gallons", "gal"],
+        Dimension::Volume,
+        3.785411784,
+    ),
+    unit(
+        &["imperial_gallons", "uk_gallons"],
+        Dimension::Volume,
+        4.54609,
+    ),
+    unit(
+        &["quarts", "quart", "us_quarts", "qt"],
+        Dimension::Volume,
+        0.946352946,
+    ),
+    unit(
+        &["imperial_quarts", "uk_quarts"],
+        Dimension::Volume,
+        1.1365225,
+    ),
+    unit(
+        &["pints", "pint", "imperial_pints", "uk_pints"],
+        Dimension::Volume,
+        0.56826125,
+    ),
+    unit(&["us_pints"], Dimension::Volume, 0.473176473),
+    unit(&["cups", "cup", "us_cups"], Dimension::Volume, 0.2365882365),
+    unit(
+        &["fluid_ounces", "us_fluid_ounces", "fl_oz"],
+        Dimension::Volume,
+        0.0295735295625,
+    ),
+    unit(
+        &["imperial_fluid_ounces", "uk_fluid_ounces"],
+        Dimension::Volume,
+        0.0284130625,
+    ),
+    unit(
+        &["tablespoons", "tbsp"],
+        Dimension::Volume,
+        0.01478676478125,
+    ),
+    unit(&["teaspoons", "tsp"], Dimension::Volume, 0.00492892159375),
+    unit(&["kilograms", "kilogram", "kg"], Dimension::Mass, 1.0),
+    unit(&["grams", "gram", "g"], Dimension::Mass, 0.001),
+    unit(
+        &["milligrams", "milligram", "mg"],
+        Dimension::Mass,
+        0.000001,
+    ),
+    unit(&["tonnes", "tonne", "t"], Dimension::Mass, 1000.0),
+    unit(
+        &["pounds", "pound", "lb", "lbs"],
+        Dimension::Mass,
+        0.45359237,
+    ),
+    unit(&["ounces", "ounce", "oz"], Dimension::Mass, 0.028349523125),
+    unit(&["stones", "stone", "st"], Dimension::Mass, 6.35029318),
+    unit(&["us_tons", "short_tons"], Dimension::Mass, 907.18474),
+    unit(
+        &["imperial_tons", "long_tons"],
+        Dimension::Mass,
+        1016.0469088,
+    ),
+    unit(&["kelvin", "k"], Dimension::Temperature, 1.0),
+    Unit {
+        names: &["celsius", "c"],
+        dimension: Dimension::Temperature,
+        scale: 1.0,
+        offset: 273.15,
+    },
+    Unit {
+        names: &["fahrenheit", "f"],
+        dimension: Dimension::Temperature,
+        scale: 5.0 / 9.0,
+        offset: 273.15 - 32.0 * 5.0 / 9.0,
+    },
+    unit(&["rankine", "r"], Dimension::Temperature, 5.0 / 9.0),
+];
+
+fn find_unit(name: &str) -> Result<&'static Unit, String> {
+    UNITS
+        .iter()
+        .find(|unit| {
+            unit.names
+                .iter()
+                .any(|unit_name| unit_name.eq_ignore_ascii_case(name))
+        })
+        .ok_or_else(|| format!("Unknown unit `{name}`"))
+}
+
+fn convert(value: f64, from: &str, to: &str) -> Result<f64, String> {
+    if !value.is_finite() {
+        return Err("The value must be a finite number".to_string());
+    }
+    let (from, to) = (find_unit(from)?, find_unit(to)?);
+    if from.dimension != to.dimension {
+        return Err(format!(
+            "Can't convert {} to {}",
+            from.dimension, to.dimension
+        ));
+    }
+
+    let base = value * from.scale + from.offset;
+    if from.dimension == Dimension::Temperature && base < 0.0 {
+        return Err("Temperature below absolute zero".to_string());
+    }
+    // JSON has no infinity, a result out of the f64 range would be rendered as null
+    let converted = (base - to.offset) / to.scale;
+    if !converted.is_finite() {
+        return Err(format!("The value is out of range in {}", to.names[0]));
+    }
+    Ok(converted)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Conversion {
+    value: f64,
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Converted {
+    value: f64,
+    unit: String,
+}
+
 #[skip_serializing_none]
 #[derive(Debug, Default, Deserialize, Serialize)]
 struct MilkUnits {
-    gallons: Option<f32>,
-    liters: Option<f32>,
-    litres: Option<f32>,
-    pints: Option<f32>,
+    gallons: Option<f64>,
+    liters: Option<f64>,
+    litres: Option<f64>,
+    pints: Option<f64>,
     #[serde(flatten, skip_serializing)]
     extra: HashMap<String, serde_json::Value>,
 }
 
+impl MilkUnits {
+    // the single unit given and the one it converts to, the spelling picks the direction
+    fn legacy_conversion(&self) -> Option<(f64, &'static str, &'static str)> {
+        if !self.extra.is_empty() {
+            return None;
+        }
+        match (self.gallons, self.liters, self.litres, self.pints) {
+            (Some(gallons), None, None, None) => Some((gallons, "gallons", "liters")),
+            (None, Some(liters), None, None) => Some((liters, "liters", "gallons")),
+            (None, None, Some(litres), None) => Some((litres, "litres", "pints")),
+            (None, None, None, Some(pints)) => Some((pints, "pints", "litres")),
+            _ => None,
+        }
+    }
+
+    fn single(unit: &str, value: f64) -> Self {
+        let mut units = Self::default();
+        match unit {
+            "gallons" => units.gallons = Some(value),
+            "liters" => units.liters = Some(value),
+            "litres" => units.litres = Some(value),
+            _ => units.pints = Some(value),
+        }
+        units
+    }
+}
+
+type RateLimitHeaders = AppendHeaders<Vec<(HeaderName, String)>>;
+
+// how long until `tokens` holds `needed` tokens, zero if it already does. saturates at
+// `Duration::MAX` for buckets that would take longer than that to fill
+fn time_until(
+    config: &BucketConfig,
+    tokens: &ClientTokens,
+    needed: usize,
+    now: Instant,
+) -> Duration {
+    let refills = needed.saturating_sub(tokens.tokens).div_ceil(config.refill);
+    let due = u32::try_from(refills)
+        .ok()
+        .and_then(|refills| config.interval().checked_mul(refills))
+        .unwrap_or(Duration::MAX);
+    due.saturating_sub(now.saturating_duration_since(tokens.refilled_at))
+}
+
+// `RateLimit-*` headers as in the IETF draft, plus `Retry-After` when `needed` tokens weren't
+// available
+fn rate_limit_headers(
+    config: &BucketConfig,
+    tokens: &ClientTokens,
+    needed: Option<usize>,
+) -> RateLimitHeaders {
+    let now = Instant::now();
+    // whole seconds rounded up, the float to integer cast saturates
+    let seconds =
+        |needed: usize| time_until(config, tokens, needed, now).as_secs_f64().ceil() as u64;
+
+    let mut headers = vec![
+        (
+            HeaderName::from_static("ratelimit-limit"),
+            config.max.to_string(),
+        ),
+        (
+            HeaderName::from_static("ratelimit-remaining"),
+            tokens.tokens.to_string(),
+        ),
+        (
+            HeaderName::from_static("ratelimit-reset"),
+            seconds(config.max).to_string(),
+        ),
+    ];
+    if let Some(needed) = needed {
+        headers.push((header::RETRY_AFTER, seconds(needed).max(1).to_string()));
+    }
+    AppendHeaders(headers)
+}
+
+#[derive(Deserialize)]
+pub struct WaitQuery {
+    wait: Option<f64>,
+}
+
 pub async fn post_milk(
     headers: HeaderMap,
     State(state): State<Milk>,
+    Query(query): Query<WaitQuery>,
+    RawQuery(raw_query): RawQuery,
     body: String,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    if !state.limiter.lock().unwrap().try_acquire(1) {
-        return Ok((
+) -> Response {
+    let wait = match wait_time(&headers, query.wait, state.max_wait) {
+        Ok(wait) => wait,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let client = state.client(&headers);
+    let (withdrawn, rate_limit) = match state.take(MILK, &client, 1, wait).await {
+        Ok(taken) => taken,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let outcome = if withdrawn {
+        Outcome::Withdrawn
+    } else {
+        Outcome::Rejected
+    };
+    state.ledger.lock().unwrap().record(&client, outcome);
+
+    if !withdrawn {
+        return (
             StatusCode::TOO_MANY_REQUESTS,
-            "No milk available\n".to_string(),
+            rate_limit,
+            "No milk available\n",
+        )
+            .into_response();
+    }
+    (
+        rate_limit,
+        convert_milk(&headers, raw_query.as_deref(), &body),
+    )
+        .into_response()
+}
+
+type Units = serde_json::Map<String, serde_json::Value>;
+
+// form fields and query parameters are all strings, anything that reads as a number is one
+fn form_units<'a>(pairs: impl Iterator<Item = (Cow<'a, str>, Cow<'a, str>)>) -> Units {
+    pairs
+        .map(|(key, value)| {
+            let number = value
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64);
+            let value = number.map_or_else(
+                || serde_json::Value::String(value.to_string()),
+                serde_json::Value::Number,
+            );
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+// query parameters that ask for a conversion, any others (`wait`, cache busters) are ignored
+const CONVERSION_KEYS: &[&str] = &[
+    "value", "from", "to", "gallons", "liters", "litres", "pints",
+];
+
+// the units to convert from a JSON, YAML, TOML or form body, or from the query string of a
+// request without one. `None` when the request doesn't ask for a conversion at all
+fn milk_units(headers: &HeaderMap, query: Option<&str>, body: &str) -> Result<Option<Units>, ()> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let essence = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+    if essence
+        .is_some_and(|essence| essence.eq_ignore_ascii_case("application/x-www-form-urlencoded"))
+    {
+        return Ok(Some(form_units(form_urlencoded::parse(body.as_bytes()))));
+    }
+
+    match content_type.and_then(Format::from_content_type) {
+        Some(Format::Json) => serde_json::from_str(body).map(Some).map_err(|_| ()),
+        Some(Format::Yaml) => serde_yaml::from_str(body).map(Some).map_err(|_| ()),
+        Some(Format::Toml) => toml::from_str(body).map(Some).map_err(|_| ()),
+        _ if body.trim().is_empty() => {
+            let params = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                .filter(|(key, _)| CONVERSION_KEYS.contains(&key.as_ref()));
+            let units = form_units(params);
+            Ok((!units.is_empty()).then_some(units))
+        }
+        _ => Ok(None),
+    }
+}
+
+// `{"value", "from", "to"}` converts between any two units of the same dimension, units holding
+// just one of `gallons`, `liters`, `litres` or `pints` are the legacy format. the result comes
+// back in the format asked for with `Accept`, JSON by default
+fn convert_milk(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    body: &str,
+) -> Result<Response, (StatusCode, String)> {
+    let bad_request = |message: String| (StatusCode::BAD_REQUEST, message);
+    let units = match milk_units(headers, query, body) {
+        Ok(Some(units)) => units,
+        Ok(None) => return Ok((StatusCode::OK, "Milk withdrawn\n").into_response()),
+        Err(()) => return Err(bad_request(String::new())),
+    };
+    let format = Format::from_accept(headers);
+
+    if ["value", "from", "to"]
+        .iter()
+        .any(|key| units.contains_key(*key))
+    {
+        let conversion: Conversion =
+            serde_json::from_value(units.into()).map_err(|err| bad_request(err.to_string()))?;
+        let value =
+            convert(conversion.value, &conversion.from, &conversion.to).map_err(bad_request)?;
+        return Ok(render(
+            format,
+            &Converted {
+                value,
+                unit: conversion.to,
+            },
         ));
     }
 
-    if let Some(b"application/json") = headers.get("Content-Type").map(|header| header.as_bytes()) {
-        let units: MilkUnits =
-            serde_json::from_str(&body).map_err(|_| (StatusCode::BAD_REQUEST, ""))?;
-        if let Some(gallons) = units.gallons {
-            if units.liters.is_some()
-                || units.litres.is_some()
-                || units.pints.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+    let units: MilkUnits =
+        serde_json::from_value(units.into()).map_err(|_| bad_request(String::new()))?;
+    let (value, from, to) = units
+        .legacy_conversion()
+        .ok_or_else(|| bad_request(String::new()))?;
+    let value = convert(value, from, to).map_err(bad_request)?;
+    Ok(render(format, &MilkUnits::single(to, value)))
+}
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    liters: Some(gallons * 3.785412),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else if let Some(liters) = units.liters {
-            if units.gallons.is_some()
-                || units.litres.is_some()
-                || units.pints.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+#[derive(Deserialize)]
+pub struct RefillQuery {
+    amount: Option<usize>,
+}
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    gallons: Some(liters / 3.785412),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else if let Some(litres) = units.litres {
-            if units.gallons.is_some()
-                || units.liters.is_some()
-                || units.pints.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+// fills the client's own milk up completely, open to everyone as it always was. A partial
+// refill with `amount` is an admin action like refilling a named bucket.
+pub async fn post_refill(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Query(query): Query<RefillQuery>,
+) -> Response {
+    if query.amount.is_some() {
+        if let Err(rejection) = authorize(&state, &headers) {
+            return rejection.into_response();
+        }
+    }
+    refill(&state, &state.client(&headers).key, MILK, query.amount)
+}
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    pints: Some(litres * 1.759754),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else if let Some(pints) = units.pints {
-            if units.gallons.is_some()
-                || units.liters.is_some()
-                || units.litres.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BucketUpdate {
+    initial: Option<usize>,
+    max: Option<usize>,
+    refill: Option<usize>,
+    interval_ms: Option<u64>,
+    cost: Option<usize>,
+}
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    litres: Some(pints / 1.759754),
-                    ..Default::default()
-                })
-                .unwrap(),
+// upper bounds for bucket settings, a day between refills and a million tokens
+const MAX_TOKENS: usize = 1_000_000;
+const MAX_INTERVAL_MS: u64 = 24 * 60 * 60 * 1000;
+
+impl BucketUpdate {
+    fn apply(&self, current: BucketConfig) -> Result<BucketConfig, (StatusCode, &'static str)> {
+        let config = BucketConfig {
+            initial: self.initial.unwrap_or(current.initial),
+            max: self.max.unwrap_or(current.max),
+            refill: self.refill.unwrap_or(current.refill),
+            interval_ms: self.interval_ms.unwrap_or(current.interval_ms),
+            cost: self.cost.unwrap_or(current.cost),
+        };
+        if config.max == 0 || config.refill == 0 || config.interval_ms == 0 || config.cost == 0 {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "max, refill, interval_ms and cost must be positive",
+            ));
+        }
+        if config.max > MAX_TOKENS || config.interval_ms > MAX_INTERVAL_MS {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "max is at most 1000000 and interval_ms at most 86400000",
             ));
-        } else {
-            return Err((StatusCode::BAD_REQUEST, ""));
         }
+        if config.cost > config.max || config.refill > config.max {
+            return Err((StatusCode::BAD_REQUEST, "cost and refill can't exceed max"));
+        }
+        Ok(BucketConfig {
+            initial: config.initial.min(config.max),
+            ..config
+        })
     }
+}
 
-    Ok((StatusCode::OK, "Milk withdrawn\n".to_string()))
+#[derive(Serialize)]
+struct BucketStatus {
+    config: BucketConfig,
+    // tokens left per tracked client, the anonymous client is the empty string
+    clients: BTreeMap<String, usize>,
 }
 
-pub async fn post_refill(State(state): State<Milk>) -> impl IntoResponse {
-    let mut limiter = state.limiter.lock().unwrap();
-    *limiter = new_limiter();
+fn bucket_status(buckets: &ClientBuckets) -> Json<BucketStatus> {
+    Json(BucketStatus {
+        config: buckets.config,
+        clients: buckets.balances(),
+    })
+}
 
-    StatusCode::OK
+// what any client may see of a bucket, only its own tokens
+#[derive(Serialize)]
+struct ClientBucketStatus {
+    name: String,
+    config: BucketConfig,
+    remaining: usize,
 }
 
-fn new_limiter() -> RateLimiter {
-    RateLimiter::builder()
-        .initial(5)
-        .max(5)
-        .refill(1)
-        .interval(Duration::from_secs(1))
-        .build()
+#[derive(Deserialize)]
+struct TakeQuery {
+    n: Option<usize>,
+    wait: Option<f64>,
 }
 
-pub fn day9_routes() -> Router {
-    let milk_state = Milk::new();
+#[derive(Serialize)]
+struct Taken<'a> {
+    name: &'a str,
+    taken: usize,
+}
+
+fn valid_bucket_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+async fn get_named_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+) -> Response {
+    let client = state.client(&headers).key;
+    let buckets = state.buckets.lock().unwrap();
+    let Some(bucket) = buckets.get(&name) else {
+        return no_bucket(&name).into_response();
+    };
+
+    // reading never starts tracking a client, so it can't push others out of the bucket
+    let remaining = bucket.peek(&client, Instant::now()).tokens;
+    Json(ClientBucketStatus {
+        name,
+        config: bucket.config,
+        remaining,
+    })
+    .into_response()
+}
+
+// creating is open to every client, up to `DAY9_BUCKETS_PER_CLIENT` buckets each unless it's an
+// admin. changing or refilling an existing bucket is left to admins so nobody can raise a limit
+// they are subject to
+async fn put_named_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+    Json(update): Json<BucketUpdate>,
+) -> Response {
+    if !valid_bucket_name(&name) {
+        let message = "Bucket names are 1 to 64 lowercase letters, digits, `-` or `_`";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let config = match update.apply(BucketConfig::default()) {
+        Ok(config) => config,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let mut buckets = state.buckets.lock().unwrap();
+    if buckets.contains_key(&name) {
+        return (
+            StatusCode::CONFLICT,
+            format!("Bucket `{name}` already exists"),
+        )
+            .into_response();
+    }
+    if buckets.len() >= state.max_buckets {
+        return (StatusCode::FORBIDDEN, "Too many buckets").into_response();
+    }
+    let creator = match authorize(&state, &headers) {
+        Ok(()) => None,
+        Err(_) => Some(state.client(&headers).key),
+    };
+    if let Some(creator) = &creator {
+        let created = buckets
+            .values()
+            .filter(|bucket| bucket.creator.as_ref() == Some(creator))
+            .count();
+        if created >= state.max_created {
+            return (
+                StatusCode::FORBIDDEN,
+                "Too many buckets created by this client",
+            )
+                .into_response();
+        }
+    }
+
+    let bucket = ClientBuckets {
+        config,
+        max_clients: state.max_clients,
+        buckets: HashMap::new(),
+        creator,
+    };
+    let created = (StatusCode::CREATED, bucket_status(&bucket)).into_response();
+    buckets.insert(name, bucket);
+    created
+}
+
+async fn take_from_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+    Query(query): Query<TakeQuery>,
+) -> Response {
+    if name == MILK {
+        return milk_route("/9/milk").into_response();
+    }
+    let units = query.n.unwrap_or(1);
+    let wait = match wait_time(&headers, query.wait, state.max_wait) {
+        Ok(wait) => wait,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let client = state.client(&headers);
+    let (taken, rate_limit) = match state.take(&name, &client, units, wait).await {
+        Ok(taken) => taken,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    if !taken {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            rate_limit,
+            format!("No {name} available\n"),
+        )
+            .into_response();
+    }
+    (
+        rate_limit,
+        Json(Taken {
+            name: &name,
+            taken: units,
+        }),
+    )
+        .into_response()
+}
+
+fn refill(state: &Milk, client: &str, name: &str, amount: Option<usize>) -> Response {
+    match state.buckets.lock().unwrap().get_mut(name) {
+        Some(bucket) => {
+            bucket.refill(client, amount, Instant::now());
+            state.changed.notify_waiters();
+            StatusCode::OK.into_response()
+        }
+        None => no_bucket(name).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct BucketRefillQuery {
+    amount: Option<usize>,
+    // the client whose tokens are refilled, the caller's own by default
+    client: Option<String>,
+}
+
+async fn refill_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+    Query(query): Query<BucketRefillQuery>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    if name == MILK {
+        return milk_route("/9/refill").into_response();
+    }
+    let client = query.client.unwrap_or_else(|| state.client(&headers).key);
+    refill(&state, &client, &name, query.amount)
+}
+
+fn authorize(state: &Milk, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    admin::authorize(state.admin_token.as_deref(), headers)
+}
+
+async fn get_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.buckets.lock().unwrap().get(&name) {
+        Some(bucket) => bucket_status(bucket).into_response(),
+        None => no_bucket(&name).into_response(),
+    }
+}
+
+async fn put_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+    Json(update): Json<BucketUpdate>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let mut buckets = state.buckets.lock().unwrap();
+    let Some(bucket) = buckets.get_mut(&name) else {
+        return no_bucket(&name).into_response();
+    };
+    match update.apply(bucket.config) {
+        Ok(config) => {
+            bucket.reconfigure(config, Instant::now());
+            state.changed.notify_waiters();
+            bucket_status(bucket).into_response()
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn delete_bucket(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    if name == MILK {
+        return (StatusCode::BAD_REQUEST, "The milk bucket can't be deleted").into_response();
+    }
+    match state.buckets.lock().unwrap().remove(&name) {
+        Some(_) => {
+            state.changed.notify_waiters();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => no_bucket(&name).into_response(),
+    }
+}
+
+async fn get_milk_bucket(headers: HeaderMap, state: State<Milk>) -> Response {
+    get_bucket(headers, state, Path(MILK.to_string())).await
+}
+
+async fn put_milk_bucket(
+    headers: HeaderMap,
+    state: State<Milk>,
+    update: Json<BucketUpdate>,
+) -> Response {
+    put_bucket(headers, state, Path(MILK.to_string()), update).await
+}
+
+#[derive(Serialize)]
+struct MinuteHistogram {
+    minute: u64,
+    #[serde(flatten)]
+    stats: MinuteStats,
+}
+
+#[derive(Serialize)]
+struct MilkStats {
+    total: u64,
+    withdrawn: u64,
+    rejected: u64,
+    rejection_rate: f64,
+    per_minute: Vec<MinuteHistogram>,
+}
+
+async fn get_stats(headers: HeaderMap, State(state): State<Milk>) -> Response {
+    let ledger = state.ledger.lock().unwrap();
+    let total = ledger.withdrawn + ledger.rejected;
+    let stats = MilkStats {
+        total,
+        withdrawn: ledger.withdrawn,
+        rejected: ledger.rejected,
+        rejection_rate: if total == 0 {
+            0.0
+        } else {
+            ledger.rejected as f64 / total as f64
+        },
+        per_minute: ledger
+            .per_minute
+            .iter()
+            .map(|(minute, stats)| MinuteHistogram {
+                minute: *minute,
+                stats: *stats,
+            })
+            .collect(),
+    };
+    render(Format::from_accept(&headers), &stats)
+}
+
+#[derive(Deserialize)]
+struct LedgerQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct LedgerPage<'a> {
+    entries: Vec<&'a LedgerEntry>,
+}
+
+// the ledger names clients by their key, which may be an API key, so it is for admins only
+async fn get_ledger(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Query(query): Query<LedgerQuery>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let ledger = state.ledger.lock().unwrap();
+    let skip = ledger
+        .entries
+        .len()
+        .saturating_sub(query.limit.unwrap_or(100));
+    render(
+        Format::from_accept(&headers),
+        &LedgerPage {
+            entries: ledger.entries.iter().skip(skip).collect(),
+        },
+    )
+}
+
+pub fn day9_routes(secrets: &SecretStore) -> Router {
+    let milk_state = Milk::new(secrets);
+    milk_state.spawn_saver();
 
     Router::new()
         .route("/9/milk", post(post_milk))
         .route("/9/refill", post(post_refill))
+        .route(
+            "/9/buckets/:name",
+            get(get_named_bucket).put(put_named_bucket),
+        )
+        .route("/9/buckets/:name/take", post(take_from_bucket))
+        .route("/9/buckets/:name/refill", post(refill_bucket))
+        .route("/9/admin/bucket", get(get_milk_bucket).put(put_milk_bucket))
+        .route(
+            "/9/admin/buckets/:name",
+            get(get_bucket).put(put_bucket).delete(delete_bucket),
+        )
+        .route("/9/stats", get(get_stats))
+        .route("/9/ledger", get(get_ledger))
         .with_state(milk_state)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_for_takes_the_trusted_hop() {
+        let cases: &[(&[&str], usize, Option<&str>)] = &[
+            (&[], 1, None),
+            (&["10.0.0.1"], 1, Some("10.0.0.1")),
+            (&["1.1.1.1, 10.0.0.1"], 1, Some("10.0.0.1")),
+            (&["1.1.1.1,10.0.0.1, 10.0.0.2"], 2, Some("10.0.0.1")),
+            // proxies appending their own header line
+            (&["1.1.1.1", "10.0.0.1"], 1, Some("10.0.0.1")),
+            (
+                &["1.1.1.1, 2.2.2.2", "10.0.0.1", "10.0.0.2"],
+                2,
+                Some("10.0.0.1"),
+            ),
+            (
+                &["1.1.1.1, 2.2.2.2", "10.0.0.1", "10.0.0.2"],
+                3,
+                Some("2.2.2.2"),
+            ),
+            // fewer hops than trusted proxies, the first one was appended by a proxy too
+            (&["10.0.0.1"], 3, Some("10.0.0.1")),
+            (&["10.0.0.1, 10.0.0.2"], 3, Some("10.0.0.1")),
+            // empty hops are never a client
+            (&[""], 1, None),
+            (&["1.1.1.1, "], 1, None),
+            (&["1.1.1.1,,10.0.0.1"], 2, None),
+            (&["1.1.1.1", ""], 1, None),
+            (&[" , 10.0.0.1"], 3, None),
+        ];
+        for (lines, proxies, expected) in cases {
+            let mut headers = HeaderMap::new();
+            for line in *lines {
+                headers.append("x-forwarded-for", line.parse().unwrap());
+            }
+            assert_eq!(
+                forwarded_for(&headers, *proxies).as_deref(),
+                *expected,
+                "{lines:?} behind {proxies} proxies"
+            );
+        }
+    }
+
+    #[test]
+    fn wait_time_from_query_or_preference() {
+        let max = ms(30_000);
+        let wait = |prefer: &[&str], query: Option<f64>| {
+            let mut headers = HeaderMap::new();
+            for line in prefer {
+                headers.append("prefer", line.parse().unwrap());
+            }
+            wait_time(&headers, query, max).map_err(|(status, _)| status)
+        };
+
+        assert_eq!(wait(&[], None), Ok(None));
+        assert_eq!(wait(&["respond-async"], None), Ok(None));
+        assert_eq!(wait(&[], Some(1.5)), Ok(Some(ms(1500))));
+        assert_eq!(wait(&["wait=5"], None), Ok(Some(ms(5000))));
+        assert_eq!(wait(&["respond-async, Wait = 2"], None), Ok(Some(ms(2000))));
+        assert_eq!(
+            wait(&["return=minimal; wait=\"0.25\""], None),
+            Ok(Some(ms(250)))
+        );
+        assert_eq!(wait(&["respond-async", "wait=3"], None), Ok(Some(ms(3000))));
+        // the query string wins over the header, even an invalid one
+        assert_eq!(wait(&["wait=5"], Some(1.0)), Ok(Some(ms(1000))));
+        assert_eq!(wait(&["wait=soon"], Some(1.0)), Ok(Some(ms(1000))));
+
+        assert_eq!(wait(&["wait=soon"], None), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(wait(&["wait="], None), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(wait(&["wait=-1"], None), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(wait(&[], Some(-0.5)), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(wait(&[], Some(f64::NAN)), Err(StatusCode::BAD_REQUEST));
+
+        // capped at `DAY9_MAX_WAIT_MS`
+        assert_eq!(wait(&[], Some(31.0)), Ok(Some(max)));
+        assert_eq!(wait(&["wait=1e9"], None), Ok(Some(max)));
+        assert_eq!(wait(&[], Some(f64::INFINITY)), Err(StatusCode::BAD_REQUEST));
+    }
+
+    fn buckets(config: BucketConfig, max_clients: usize) -> ClientBuckets {
+        ClientBuckets {
+            config,
+            max_clients,
+            buckets: HashMap::new(),
+            creator: None,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn refills_count_whole_intervals() {
+        let config = BucketConfig::default();
+        let start = Instant::now();
+        let mut tokens = ClientTokens::new(0, start);
+
+        tokens.refill(&config, start + ms(2500));
+        assert_eq!(tokens.tokens, 2);
+        // the half interval already waited still counts towards the next token
+        assert_eq!(tokens.refilled_at, start + ms(2000));
+        tokens.refill(&config, start + ms(3000));
+        assert_eq!(tokens.tokens, 3);
+
+        // a full bucket starts counting again from the moment it filled up
+        tokens.refill(&config, start + ms(60_000));
+        assert_eq!(tokens.tokens, 5);
+        assert_eq!(tokens.refilled_at, start + ms(60_000));
+
+        // a clock going backwards adds nothing
+        let mut tokens = ClientTokens::new(1, start + ms(1000));
+        tokens.refill(&config, start);
+        assert_eq!(tokens.tokens, 1);
+    }
+
+    #[test]
+    fn time_until_counts_from_the_last_refill() {
+        let config = BucketConfig {
+            refill: 2,
+            ..BucketConfig::default()
+        };
+        let start = Instant::now();
+        let tokens = ClientTokens::new(1, start);
+
+        assert_eq!(time_until(&config, &tokens, 1, start), Duration::ZERO);
+        assert_eq!(time_until(&config, &tokens, 3, start), ms(1000));
+        assert_eq!(time_until(&config, &tokens, 4, start + ms(400)), ms(1600));
+        assert_eq!(
+            time_until(&config, &tokens, 2, start + ms(5000)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            time_until(&config, &tokens, usize::MAX, start),
+            Duration::MAX
+        );
+    }
+
+    #[test]
+    fn eviction_spares_waiting_clients() {
+        let start = Instant::now();
+        let mut buckets = buckets(BucketConfig::default(), 2);
+        buckets.get("a", start).tokens = 0;
+        buckets.get("b", start + ms(1));
+        buckets.get("c", start + ms(2));
+        assert!(!buckets.buckets.contains_key("a"));
+
+        // the least recently used client is waiting, so the other one goes
+        buckets.get("b", start + ms(3)).waiting_until = Some(start + ms(1000));
+        buckets.get("d", start + ms(4));
+        assert!(buckets.buckets.contains_key("b"));
+        assert!(!buckets.buckets.contains_key("c"));
+
+        // once every client is waiting the least recently used one goes after all
+        buckets.get("d", start + ms(5)).waiting_until = Some(start + ms(1000));
+        buckets.get("e", start + ms(6));
+        assert!(!buckets.buckets.contains_key("b"));
+        assert!(buckets.buckets.contains_key("d"));
+
+        // a wait that has run out doesn't protect a client
+        buckets.buckets.get_mut("d").unwrap().waiting_until = Some(start + ms(8));
+        buckets.get("f", start + ms(2000));
+        assert!(!buckets.buckets.contains_key("d"));
+        assert!(buckets.buckets.contains_key("e"));
+    }
+
+    #[test]
+    fn peek_doesnt_track_clients() {
+        let start = Instant::now();
+        let mut buckets = buckets(BucketConfig::default(), 1);
+        buckets.get("a", start).tokens = 1;
+        assert_eq!(buckets.peek("b", start).tokens, 5);
+        assert_eq!(buckets.peek("a", start + ms(2000)).tokens, 3);
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.buckets["a"].tokens, 1);
+    }
+
+    #[test]
+    fn partial_refills_are_capped() {
+        let start = Instant::now();
+        let mut buckets = buckets(BucketConfig::default(), 10);
+        buckets.get("a", start).tokens = 1;
+        buckets.refill("a", Some(2), start);
+        assert_eq!(buckets.peek("a", start).tokens, 3);
+        buckets.refill("a", Some(10), start);
+        assert_eq!(buckets.peek("a", start).tokens, 5);
+        buckets.get("a", start).tokens = 0;
+        buckets.refill("a", None, start);
+        assert_eq!(buckets.peek("a", start).tokens, 5);
+    }
+
+    #[test]
+    fn reconfigure_keeps_current_tokens() {
+        let start = Instant::now();
+        let mut buckets = buckets(BucketConfig::default(), 10);
+        buckets.get("a", start).tokens = 0;
+        buckets.get("b", start).tokens = 5;
+
+        // 900ms into an interval, which would be nine intervals of the new length
+        let faster = BucketConfig {
+            max: 3,
+            interval_ms: 100,
+            ..BucketConfig::default()
+        };
+        buckets.reconfigure(faster, start + ms(900));
+        assert_eq!(buckets.peek("a", start + ms(900)).tokens, 0);
+        assert_eq!(buckets.peek("b", start + ms(900)).tokens, 3);
+        assert_eq!(buckets.peek("a", start + ms(999)).tokens, 0);
+        assert_eq!(buckets.peek("a", start + ms(1000)).tokens, 1);
+
+        // refills due under the old settings are kept
+        let slower = BucketConfig {
+            interval_ms: 10_000,
+            ..BucketConfig::default()
+        };
+        buckets.reconfigure(slower, start + ms(1250));
+        assert_eq!(buckets.peek("a", start + ms(1250)).tokens, 3);
+        assert_eq!(buckets.peek("a", start + ms(11_249)).tokens, 3);
+        assert_eq!(buckets.peek("a", start + ms(11_250)).tokens, 4);
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        let tolerance = 1e-9 * expected.abs().max(1.0);
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn unit_names_are_unique() {
+        let mut seen = HashMap::new();
+        for unit in UNITS {
+            for name in unit.names {
+                let previous = seen.insert(name.to_ascii_lowercase(), unit.dimension);
+                assert!(previous.is_none(), "`{name}` names two units");
+                assert_eq!(find_unit(name).unwrap().names[0], unit.names[0]);
+            }
+        }
+        assert!(find_unit("LITRES").is_ok());
+        assert!(find_unit("furlongs").is_err());
+    }
+
+    #[test]
+    fn units_match_their_definitions() {
+        let cases = [
+            (1.0, "gallons", 3.785411784, "liters"),
+            (1.0, "imperial_gallons", 4.54609, "litres"),
+            (1.0, "m3", 1000.0, "l"),
+            (1000.0, "ml", 1.0, "l"),
+            (4.0, "quarts", 1.0, "gallons"),
+            (4.0, "imperial_quarts", 1.0, "imperial_gallons"),
+            (8.0, "pints", 1.0, "imperial_gallons"),
+            (8.0, "us_pints", 1.0, "gallons"),
+            (16.0, "cups", 1.0, "gallons"),
+            (128.0, "fl_oz", 1.0, "gallons"),
+            (160.0, "imperial_fluid_ounces", 1.0, "imperial_gallons"),
+            (2.0, "tbsp", 1.0, "fl_oz"),
+            (3.0, "tsp", 1.0, "tbsp"),
+            (1000.0, "g", 1.0, "kg"),
+            (1000.0, "mg", 1.0, "g"),
+            (1.0, "t", 1000.0, "kg"),
+            (1.0, "lb", 0.45359237, "kg"),
+            (16.0, "oz", 1.0, "lb"),
+            (14.0, "lb", 1.0, "stone"),
+            (2000.0, "lb", 1.0, "short_tons"),
+            (2240.0, "lb", 1.0, "long_tons"),
+            (0.0, "celsius", 273.15, "kelvin"),
+            (212.0, "fahrenheit", 100.0, "celsius"),
+            (-40.0, "f", -40.0, "c"),
+            (0.0, "f", 459.67, "rankine"),
+            (0.0, "r", 0.0, "k"),
+        ];
+        for (value, from, expected, to) in cases {
+            assert_close(convert(value, from, to).unwrap(), expected);
+            assert_close(convert(expected, to, from).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn every_unit_round_trips() {
+        for unit in UNITS {
+            let name = unit.names[0];
+            let base = UNITS
+                .iter()
+                .find(|base| base.dimension == unit.dimension)
+                .unwrap()
+                .names[0];
+            let value = convert(12.5, name, base).unwrap();
+            assert_close(convert(value, base, name).unwrap(), 12.5);
+        }
+    }
+
+    #[test]
+    fn invalid_conversions() {
+        assert!(convert(1.0, "liters", "kg").is_err());
+        assert!(convert(1.0, "celsius", "liters").is_err());
+        assert!(convert(1.0, "liters", "furlongs").is_err());
+        assert!(convert(-274.0, "celsius", "kelvin").is_err());
+        assert!(convert(-1.0, "rankine", "fahrenheit").is_err());
+        assert!(convert(1e308, "m3", "ml").is_err());
+        assert!(convert(f64::MAX, "gallons", "liters").is_err());
+        assert!(convert(f64::NAN, "liters", "gallons").is_err());
+        assert!(convert(f64::INFINITY, "celsius", "kelvin").is_err());
+        assert_close(convert(1e300, "m3", "ml").unwrap(), 1e306);
+    }
+
+    fn legacy(json: &str) -> Option<(f64, &'static str, &'static str)> {
+        serde_json::from_str::<MilkUnits>(json)
+            .unwrap()
+            .legacy_conversion()
+    }
+
+    #[test]
+    fn legacy_directions() {
+        assert_eq!(
+            legacy(r#"{"gallons": 2}"#),
+            Some((2.0, "gallons", "liters"))
+        );
+        assert_eq!(legacy(r#"{"liters": 2}"#), Some((2.0, "liters", "gallons")));
+        assert_eq!(legacy(r#"{"litres": 2}"#), Some((2.0, "litres", "pints")));
+        assert_eq!(legacy(r#"{"pints": 2}"#), Some((2.0, "pints", "litres")));
+        assert_eq!(legacy(r#"{"gallons": 2, "liters": 1}"#), None);
+        assert_eq!(legacy(r#"{"gallons": 2, "cups": 1}"#), None);
+        assert_eq!(legacy("{}"), None);
+    }
+
+    #[test]
+    fn legacy_values() {
+        // US gallons to litres and imperial pints to litres, as the legacy format always had them
+        assert_close(convert(1.0, "gallons", "liters").unwrap(), 3.785411784);
+        assert_close(
+            convert(1.0, "liters", "gallons").unwrap(),
+            0.264172052358148,
+        );
+        assert_close(convert(1.0, "litres", "pints").unwrap(), 1.759753986392702);
+        assert_close(convert(1.0, "pints", "litres").unwrap(), 0.56826125);
+
+        let units = MilkUnits::single("liters", 7.5);
+        assert_eq!(serde_json::to_string(&units).unwrap(), r#"{"liters":7.5}"#);
+    }
+}
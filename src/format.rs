@@ -0,0 +1,208 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+// response format picked from the `Accept` header, or request format from `Content-Type`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Format {
+    Text,
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    // `media_type` is the lowercased type/subtype without parameters
+    pub(crate) fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "text/plain" | "text/*" | "*/*" => Some(Format::Text),
+            "application/json" | "text/json" => Some(Format::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+                Some(Format::Yaml)
+            }
+            "application/toml" | "application/x-toml" | "text/toml" | "text/x-toml" => {
+                Some(Format::Toml)
+            }
+            // structured syntax suffixes, e.g. `application/vnd.cargo+json`
+            _ if media_type.ends_with("+json") => Some(Format::Json),
+            _ if media_type.ends_with("+yaml") => Some(Format::Yaml),
+            _ if media_type.ends_with("+toml") => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    // a full `Content-Type` value, bodies are read as UTF-8 so other charsets aren't supported
+    pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+        let mut parts = content_type.split(';').map(str::trim);
+        let media_type = parts.next()?.to_ascii_lowercase();
+        let utf8 = parts
+            .filter_map(|param| param.split_once('='))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+            .all(|(_, charset)| {
+                let charset = charset.trim().trim_matches('"');
+                charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("us-ascii")
+            });
+        utf8.then(|| Format::from_media_type(&media_type)).flatten()
+    }
+
+    pub(crate) fn from_accept(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+        else {
+            return Format::Text;
+        };
+
+        let mut candidates = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let format = Format::from_media_type(&parts.next()?.to_ascii_lowercase())?;
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((format, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+        // stable, so equally weighted ranges keep the order the client listed them in
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        candidates
+            .first()
+            .map(|(format, _)| *format)
+            .unwrap_or(Format::Text)
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Yaml => "application/yaml",
+            Format::Toml => "application/toml",
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Json => "JSON",
+            Format::Yaml => "YAML",
+            Format::Toml => "TOML",
+        }
+    }
+}
+
+// structured formats only, plain text falls back to JSON. Not every value fits every format,
+// TOML has no null and no integers above i64::MAX, which is a 500 rather than a panic.
+pub(crate) fn render<T: Serialize>(format: Format, value: &T) -> Response {
+    let (format, body) = match format {
+        Format::Text | Format::Json => (
+            Format::Json,
+            serde_json::to_string(value).map_err(|err| err.to_string()),
+        ),
+        Format::Yaml => (
+            Format::Yaml,
+            serde_yaml::to_string(value).map_err(|err| err.to_string()),
+        ),
+        Format::Toml => (
+            Format::Toml,
+            toml::to_string(value).map_err(|err| err.to_string()),
+        ),
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render the response as {}: {err}", format.name()),
+            )
+                .into_response()
+        }
+    };
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, format.content_type())],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> Format {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        Format::from_accept(&headers)
+    }
+
+    #[test]
+    fn content_types() {
+        let cases = [
+            ("application/json", Some(Format::Json)),
+            ("Application/JSON; charset=UTF-8", Some(Format::Json)),
+            ("text/json", Some(Format::Json)),
+            ("application/vnd.cargo+json", Some(Format::Json)),
+            ("application/yaml", Some(Format::Yaml)),
+            ("text/x-yaml; charset=\"utf-8\"", Some(Format::Yaml)),
+            ("application/toml", Some(Format::Toml)),
+            (
+                "application/x-toml ; charset = us-ascii",
+                Some(Format::Toml),
+            ),
+            ("text/plain", Some(Format::Text)),
+            ("application/json; boundary=x", Some(Format::Json)),
+            ("application/json; charset=latin1", None),
+            ("application/toml; charset=utf-16", None),
+            ("application/xml", None),
+            ("", None),
+        ];
+        for (content_type, format) in cases {
+            assert_eq!(
+                Format::from_content_type(content_type),
+                format,
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn accept_picks_highest_quality() {
+        assert_eq!(accept("application/json"), Format::Json);
+        assert_eq!(accept("APPLICATION/TOML"), Format::Toml);
+        assert_eq!(
+            accept("application/json;q=0.5, application/yaml"),
+            Format::Yaml
+        );
+        assert_eq!(
+            accept("application/yaml; q=0.2, application/toml;q=0.9"),
+            Format::Toml
+        );
+        assert_eq!(
+            accept("application/xml, application/toml;q=0.1"),
+            Format::Toml
+        );
+    }
+
+    #[test]
+    fn accept_keeps_client_order_on_ties() {
+        assert_eq!(accept("application/toml, application/json"), Format::Toml);
+        assert_eq!(
+            accept("application/yaml;q=0.5, application/json;q=0.5"),
+            Format::Yaml
+        );
+    }
+
+    #[test]
+    fn accept_falls_back_to_text() {
+        assert_eq!(Format::from_accept(&HeaderMap::new()), Format::Text);
+        assert_eq!(accept("*/*"), Format::Text);
+        assert_eq!(accept("application/xml"), Format::Text);
+        assert_eq!(accept("application/json;q=0"), Format::Text);
+        assert_eq!(accept("application/json;q=abc"), Format::Json);
+    }
+}
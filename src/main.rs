@@ -4,8 +4,14 @@ mod day5;
 mod day9;
 mod day12;
 mod day16;
+#[cfg(test)]
+mod devserver;
+mod admin;
+mod format;
+mod path_error;
 
 use axum::{Router};
+use shuttle_runtime::SecretStore;
 use crate::day0::day0_routes;
 use crate::day2::day2_routes;
 use crate::day5::day5_routes;
@@ -13,13 +19,13 @@ use crate::day9::day9_routes;
 use crate::day12::day12_routes;
 
 #[shuttle_runtime::main]
-async fn main() -> shuttle_axum::ShuttleAxum {
+async fn main(#[shuttle_runtime::Secrets] secrets: SecretStore) -> shuttle_axum::ShuttleAxum {
     let router = Router::new();
     let router = day0_routes(router);
     let router = day2_routes(router);
-    let router = day5_routes(router);
+    let router = day5_routes(router, &secrets);
     let router = router
-        .merge(day9_routes())
+        .merge(day9_routes(&secrets))
         .merge(day12_routes())
         .merge(day16::day16_routes());
     Ok(router.into())
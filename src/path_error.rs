@@ -0,0 +1,24 @@
+use std::fmt;
+
+// the field a `serde_path_to_error` error points at, `None` for the document itself. serde
+// reports a missing field at its parent, so its name is taken from the message.
+pub(crate) fn error_field(path: &serde_path_to_error::Path, message: &str) -> Option<String> {
+    let path = path.to_string();
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|field| field.strip_suffix('`'));
+    match missing {
+        Some(field) if path == "." => Some(field.to_string()),
+        Some(field) => Some(format!("{path}.{field}")),
+        None => (path != ".").then_some(path),
+    }
+}
+
+// message and field of an error, for formats whose messages carry no location
+pub(crate) fn describe<E: fmt::Display>(
+    err: &serde_path_to_error::Error<E>,
+) -> (String, Option<String>) {
+    let message = err.inner().to_string();
+    let field = error_field(err.path(), &message);
+    (message, field)
+}